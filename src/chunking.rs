@@ -0,0 +1,155 @@
+use std::collections::{BTreeMap, HashMap};
+use serde_json::Value;
+
+/// Tokens kept free for the answer of the model.
+const ANSWER_TOKENS: usize = 1024;
+/// Smallest part a document is split into, even if the prompt leaves less room.
+const MIN_CHUNK_TOKENS: usize = 256;
+/// Conservative token estimate: English averages about four characters per
+/// token, German and French with their long words rather three.
+const CHARS_PER_TOKEN: usize = 3;
+/// Paperless separates the pages of the content with form feeds.
+const PAGE_BREAK: char = '\u{c}';
+
+/// How much of a document is sent to the model at once.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Chunking {
+    /// Context size of the model in tokens, documents are not split if not set
+    pub context_size: Option<usize>,
+    /// Only the first pages are analyzed
+    pub max_pages: Option<usize>,
+}
+
+impl Chunking {
+    /// The parts of the content that fit together with `prompt` into the
+    /// context of the model, at least one.
+    pub fn chunks<'d>(&self, content: &'d str, prompt: &str) -> Vec<&'d str> {
+        let content = match self.max_pages {
+            Some(pages) => first_pages(content, pages),
+            None => content,
+        };
+        match self.context_size {
+            Some(size) => split(content, size.saturating_sub(estimate_tokens(prompt) + ANSWER_TOKENS).max(MIN_CHUNK_TOKENS)),
+            None => vec![content],
+        }
+    }
+}
+
+/// Rough token count, tokenizers differ so it can still be off for some
+/// models or scripts.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+fn first_pages(content: &str, pages: usize) -> &str {
+    match content.match_indices(PAGE_BREAK).nth(pages.saturating_sub(1)) {
+        Some((end, _)) => &content[..end],
+        None => content,
+    }
+}
+
+/// Splits the text into parts of at most `max_tokens`, preferably at page
+/// breaks, paragraphs, lines or words.
+fn split(text: &str, max_tokens: usize) -> Vec<&str> {
+    let max_chars = max_tokens * CHARS_PER_TOKEN;
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while rest.chars().count() > max_chars {
+        let window_end = rest.char_indices().nth(max_chars).map_or(rest.len(), |(i, _)| i);
+        let window = &rest[..window_end];
+        let cut = [PAGE_BREAK.to_string().as_str(), "\n\n", "\n", " "]
+            .iter()
+            .find_map(|separator| window.rfind(separator).filter(|&i| i > 0).map(|i| i + separator.len()))
+            .unwrap_or(window_end);
+        chunks.push(&rest[..cut]);
+        rest = &rest[cut..];
+    }
+    chunks.push(rest);
+    chunks.retain(|chunk| !chunk.trim().is_empty());
+    if chunks.is_empty() {
+        chunks.push(text);
+    }
+    chunks
+}
+
+/// Combines the values extracted from every part of a document. Fields with
+/// one value take it, fields for which the parts disagree are returned with
+/// every candidate so the model can choose.
+pub fn merge_candidates<'a>(
+    answers: impl IntoIterator<Item = &'a HashMap<String, Option<Value>>>,
+) -> (HashMap<String, Option<Value>>, BTreeMap<String, Vec<Value>>) {
+    let mut candidates: BTreeMap<String, Vec<Value>> = BTreeMap::new();
+    for answer in answers {
+        for (key, value) in answer {
+            let values = candidates.entry(key.clone()).or_default();
+            match value {
+                Some(value) if !is_empty(value) && !values.iter().any(|known| same_value(known, value)) => values.push(value.clone()),
+                _ => {}
+            }
+        }
+    }
+    let merged = candidates.iter().map(|(key, values)| (key.clone(), values.first().cloned())).collect();
+    candidates.retain(|_, values| values.len() > 1);
+    (merged, candidates)
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::String(text) => text.trim().is_empty(),
+        _ => false,
+    }
+}
+
+fn same_value(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) => a.trim().eq_ignore_ascii_case(b.trim()),
+        _ => a == b,
+    }
+}
+
+/// Asks the model to choose between the values found in different parts.
+pub fn reduce_prompt(conflicts: &BTreeMap<String, Vec<Value>>) -> String {
+    format!(
+        "The document was too long to be analyzed at once, so its parts were analyzed one by one. \
+        These fields got different values in different parts: {}. \
+        Choose the value that describes the whole document best for each of these fields. \
+        Answer only with a JSON object with the field names as keys, no additional text or explanation.",
+        serde_json::to_string(conflicts).unwrap_or_default()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_chunks() {
+        let content = "Page one\u{c}Page two\n\nsecond paragraph\u{c}Page three";
+        assert_eq!(Chunking { context_size: None, max_pages: Some(2) }.chunks(content, ""), vec!["Page one\u{c}Page two\n\nsecond paragraph"]);
+        assert_eq!(Chunking::default().chunks(content, "prompt"), vec![content]);
+
+        assert_eq!(split(content, 4), vec!["Page one\u{c}", "Page two\n\n", "second ", "paragraph\u{c}", "Page three"]);
+        assert_eq!(split("abcdefghij", 1), vec!["abc", "def", "ghi", "j"]);
+        assert_eq!(split("", 10), vec![""]);
+        let long = "word ".repeat(1000);
+        let chunks = Chunking { context_size: Some(1024 + 256), max_pages: None }.chunks(&long, "");
+        assert_eq!(chunks.len(), 7);
+        assert!(chunks.iter().all(|chunk| estimate_tokens(chunk) <= 256));
+    }
+
+    #[test]
+    fn test_merge_candidates() {
+        let answers: Vec<HashMap<String, Option<Value>>> = vec![
+            serde_json::from_value(json!({ "title": "Lease contract", "total": null, "sender": "ACME" })).unwrap(),
+            serde_json::from_value(json!({ "title": "Appendix B", "total": "EUR1200", "sender": " acme" })).unwrap(),
+            serde_json::from_value(json!({ "title": "", "total": null, "sender": null })).unwrap(),
+        ];
+        let (merged, conflicts) = merge_candidates(&answers);
+        assert_eq!(merged["total"], Some(json!("EUR1200")));
+        assert_eq!(merged["sender"], Some(json!("ACME")));
+        assert_eq!(conflicts.keys().collect::<Vec<_>>(), vec!["title"]);
+        assert_eq!(conflicts["title"], vec![json!("Lease contract"), json!("Appendix B")]);
+    }
+}
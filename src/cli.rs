@@ -0,0 +1,180 @@
+use std::path::PathBuf;
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use clap::{ArgGroup, Args, Parser, Subcommand};
+
+/// Extracts metadata from Paperless documents with an LLM.
+///
+/// Settings are read from the configuration file and environment variables,
+/// the options below take precedence over both.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(flatten)]
+    pub options: GlobalOptions,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Args, Debug)]
+pub struct GlobalOptions {
+    /// Configuration file, defaults to DOCLYTICS_CONFIG or ./doclytics.toml
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Base URL of the Paperless instance
+    #[arg(long, global = true)]
+    pub base_url: Option<String>,
+
+    /// Paperless API token
+    #[arg(long, global = true)]
+    pub token: Option<String>,
+
+    /// Query selecting the documents to process, defaults to all documents
+    /// without marker
+    #[arg(long, global = true)]
+    pub filter: Option<String>,
+
+    /// How processed documents are marked: field:<name>, tag:<name> or local:<path>
+    #[arg(long, global = true)]
+    pub marker: Option<String>,
+
+    /// LLM server protocol: ollama or openai
+    #[arg(long, global = true)]
+    pub llm_backend: Option<String>,
+
+    /// Model to use, overrides the model configured for the backend
+    #[arg(long, global = true)]
+    pub model: Option<String>,
+
+    /// Print the changes instead of writing them to Paperless
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Process every document matching the filter once (default)
+    Run,
+    /// Keep running and process new documents matching the filter periodically
+    Daemon,
+    /// Listen for document-added webhooks from Paperless and process those documents
+    Serve,
+    /// Process the given documents, even if they are already marked
+    Process {
+        #[arg(required = true)]
+        document_ids: Vec<u32>,
+    },
+    /// Process the documents whose content, model or prompt changed since their
+    /// last result, even if they are already marked. Requires the state database
+    ReprocessStale {
+        /// Query selecting the documents to check, overrides REPROCESS_FILTER
+        #[arg(long)]
+        query: Option<String>,
+        /// Process at most this many documents, overrides REPROCESS_MAX_DOCUMENTS
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Process the document Paperless just consumed, for use as PAPERLESS_POST_CONSUME_SCRIPT
+    PostConsume {
+        /// Id of the document, set by Paperless
+        #[arg(long, env = "DOCUMENT_ID")]
+        document_id: u32,
+    },
+    /// Like `run` or `process`, but only print the changes that would be made
+    DryRun {
+        document_ids: Vec<u32>,
+    },
+    /// List the custom fields, tags, document types and correspondents
+    ListFields,
+    /// Check the configuration and the connections to Paperless and the LLM
+    CheckConfig,
+    /// Remove the marker so the documents are processed again, also after a rollback
+    ResetMarker {
+        #[arg(required = true)]
+        document_ids: Vec<u32>,
+    },
+    /// Restore documents to the values they had before doclytics changed them,
+    /// requires the state database
+    #[command(group(ArgGroup::new("selection").required(true).multiple(true).args(["run", "document_ids", "since", "until"])))]
+    Rollback {
+        /// Only changes made in this run
+        #[arg(long)]
+        run: Option<i64>,
+        /// Only changes of these documents
+        #[arg(long = "document")]
+        document_ids: Vec<u32>,
+        /// Only changes made at or after this time (RFC 3339 or YYYY-MM-DD, UTC)
+        #[arg(long, value_parser = parse_time)]
+        since: Option<String>,
+        /// Only changes made before this time (RFC 3339 or YYYY-MM-DD, UTC)
+        #[arg(long, value_parser = parse_time)]
+        until: Option<String>,
+    },
+    /// Review the changes queued for approval
+    Review {
+        #[command(subcommand)]
+        action: ReviewAction,
+    },
+    /// Show the recorded results of the documents, requires the state database
+    History {
+        #[arg(required = true)]
+        document_ids: Vec<u32>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ReviewAction {
+    /// List the pending proposals
+    List,
+    /// Show what a proposal would change on the document as it is now
+    Show { proposal_id: i64 },
+    /// Edit the proposed changes in $EDITOR
+    Edit { proposal_id: i64 },
+    /// Apply the proposed changes
+    Approve {
+        #[arg(required = true)]
+        proposal_ids: Vec<i64>,
+    },
+    /// Discard the proposed changes and mark the documents as processed
+    Reject {
+        #[arg(required = true)]
+        proposal_ids: Vec<i64>,
+    },
+}
+
+/// Normalizes a time to the format of the journal timestamps.
+fn parse_time(value: &str) -> Result<String, String> {
+    let time = match DateTime::parse_from_rfc3339(value) {
+        Ok(time) => time.with_timezone(&Utc),
+        Err(_) => NaiveDate::parse_from_str(value, "%Y-%m-%d")
+            .map_err(|_| format!("'{}' is not a date (YYYY-MM-DD) or an RFC 3339 time", value))?
+            .and_hms_opt(0, 0, 0)
+            .map(|time| time.and_utc())
+            .ok_or_else(|| format!("'{}' is not a valid date", value))?,
+    };
+    Ok(time.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cli() {
+        let cli = Cli::try_parse_from(["doclytics", "process", "12", "13", "--marker", "tag:done"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Process { ref document_ids }) if *document_ids == vec![12, 13]));
+        assert_eq!(cli.options.marker.as_deref(), Some("tag:done"));
+
+        assert!(Cli::try_parse_from(["doclytics", "process"]).is_err());
+        assert!(Cli::try_parse_from(["doclytics", "process", "first"]).is_err());
+
+        let cli = Cli::try_parse_from(["doclytics", "reprocess-stale", "--limit", "50"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::ReprocessStale { query: None, limit: Some(50) })));
+
+        let cli = Cli::try_parse_from(["doclytics", "rollback", "--since", "2024-05-01", "--until", "2024-05-02T12:00:00+02:00"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Rollback { since: Some(ref since), until: Some(ref until), .. })
+            if since == "2024-05-01T00:00:00Z" && until == "2024-05-02T10:00:00Z"));
+        assert!(Cli::try_parse_from(["doclytics", "rollback"]).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use reqwest::Client;
+use crate::config::Config;
+use crate::llm_api::LlmBackend;
+use crate::marker::{Marker, MarkerStrategy};
+use crate::error::ResponseError;
+use crate::marker::LocalStore;
+use crate::state::{Selection, StateStore};
+use crate::paperless::{get_default_fields, get_document, patch_document, query_custom_fields, PaperlessDefaultFieldType};
+
+/// Prints the custom fields and the tags, document types and correspondents
+/// the LLM can choose from.
+pub async fn list_fields(client: &Client, base_url: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let fields = query_custom_fields(client, base_url).await?;
+    println!("Custom fields:");
+    for field in &fields {
+        let options: Vec<&str> = field.extra_data.iter().flat_map(|extra| extra.select_options.iter().map(|o| o.label())).collect();
+        match options.is_empty() {
+            true => println!("  {:>4}  {} ({})", field.id, field.name, field.data_type),
+            false => println!("  {:>4}  {} ({}: {})", field.id, field.name, field.data_type, options.join(", ")),
+        }
+    }
+    let field_types = [
+        ("Tags", PaperlessDefaultFieldType::Tag),
+        ("Document types", PaperlessDefaultFieldType::DocumentType),
+        ("Correspondents", PaperlessDefaultFieldType::Correspondent),
+    ];
+    for (title, field_type) in field_types {
+        println!("{}:", title);
+        for object in get_default_fields(client, base_url, field_type).await? {
+            println!("  {:>4}  {}", object.id.map_or_else(|| "-".to_string(), |id| id.to_string()), object.name);
+        }
+    }
+    Ok(())
+}
+
+/// Verifies the connection to Paperless and the LLM without changing
+/// anything. Fails if any check failed.
+pub async fn check_config(client: &Client, llm: &dyn LlmBackend, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let (base_url, marker_strategy) = (config.base_url.as_str(), &config.marker);
+    let model = llm.model_info();
+    println!("[ok]   Configuration is valid");
+    println!("Paperless:   {}", base_url);
+    println!("Filter:      {}", config.filter);
+    println!("Marker:      {:?}", marker_strategy);
+    println!("Mode:        custom fields {:?}, tags {:?}, document type {:?}, correspondent {:?}", config.mode, config.tags.mode, config.document_type.mode, config.correspondent.mode);
+    println!("LLM:         {} model {} (structured output: {})", model.backend, model.name, model.structured_output);
+
+    let mut failed = false;
+    let mut fields = match query_custom_fields(client, base_url).await {
+        Ok(fields) => {
+            println!("[ok]   Paperless is reachable, {} custom fields", fields.len());
+            fields
+        }
+        Err(e) => {
+            println!("[fail] Paperless: {}", e);
+            failed = true;
+            Vec::new()
+        }
+    };
+    if !failed {
+        match Marker::bootstrap(client, base_url, marker_strategy, &mut fields, true).await {
+            Ok(marker) if marker.field_id() == Some(0) || marker.tag_id() == Some(0) => {
+                println!("[ok]   Marker does not exist yet and is created on the first run")
+            }
+            Ok(_) => println!("[ok]   Marker"),
+            Err(e) => {
+                println!("[fail] Marker: {}", e);
+                failed = true;
+            }
+        }
+    }
+    match llm.check().await {
+        Ok(()) => println!("[ok]   LLM is reachable and serves {}", model.name),
+        Err(e) => {
+            println!("[fail] LLM: {}", e);
+            failed = true;
+        }
+    }
+
+    match failed {
+        true => Err(Box::new(ResponseError::Other("configuration check failed".to_string()))),
+        false => Ok(()),
+    }
+}
+
+/// Removes the marker from the documents so they are processed again,
+/// including documents that were rolled back.
+pub async fn reset_marker(client: &Client, config: &Config, document_ids: &[u32]) -> Result<(), Box<dyn std::error::Error>> {
+    let base_url = config.base_url.as_str();
+    let state = config.state_db.as_deref().map(StateStore::open).transpose()?;
+    let mut fields = query_custom_fields(client, base_url).await?;
+    let marker = Marker::bootstrap(client, base_url, &config.marker, &mut fields, true).await?;
+    for document_id in document_ids {
+        let document = get_document(client, base_url, *document_id).await?;
+        match marker.reset(client, base_url, &document).await? {
+            true => slog_scope::info!("Reset marker of document {}", document_id),
+            false => slog_scope::info!("Document {} is not marked", document_id),
+        }
+        if let Some(state) = &state {
+            if state.clear_rollback(*document_id)? {
+                slog_scope::info!("Document {} was rolled back and is processed again", document_id);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prints every recorded result of the documents, oldest first.
+pub fn history(config: &Config, document_ids: &[u32]) -> Result<(), Box<dyn std::error::Error>> {
+    let state = open_state(config)?;
+    for document_id in document_ids {
+        let records = state.history(*document_id)?;
+        if records.is_empty() {
+            println!("Document {}: no results recorded", document_id);
+            continue;
+        }
+        println!("Document {}:", document_id);
+        for record in records {
+            println!(
+                "  {}  {:<13}  model {}  prompt {}  content {}",
+                record.finished_at,
+                record.status.as_str(),
+                record.model,
+                &record.prompt_version[..12],
+                &record.content_hash[..12]
+            );
+            println!("    parsed:  {}", record.parsed_result);
+            if record.repairs.as_object().is_some_and(|repairs| !repairs.is_empty()) {
+                println!("    repairs: {}", record.repairs);
+            }
+            if let Some(changes) = record.applied_changes {
+                println!("    changes: {}", changes);
+            }
+            if let Some(error) = record.error {
+                println!("    error:   {}", error);
+            }
+        }
+        for entry in state.document_journal(*document_id)? {
+            let rolled_back = entry.rolled_back_at.map(|time| format!(", rolled back {}", time)).unwrap_or_default();
+            println!("  run {} at {}{}", entry.run_id, entry.applied_at, rolled_back);
+            println!("    changed:  {}", entry.changes);
+            println!("    previous: {}", entry.previous);
+        }
+    }
+    Ok(())
+}
+
+/// Restores the documents to the values saved in the journal, newest change
+/// first, so documents changed in several selected runs end up in their
+/// oldest state. The marker is restored as well, but the documents are
+/// skipped until their marker is reset, so a running daemon does not apply
+/// the same changes again.
+pub async fn rollback(client: &Client, config: &Config, selection: Selection, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let state = open_state(config)?;
+    let entries = state.journal_entries(&selection)?;
+    if entries.is_empty() {
+        println!("Nothing to roll back");
+        return Ok(());
+    }
+    let local_store = match &config.marker {
+        MarkerStrategy::Local(path) if !dry_run => Some(LocalStore::open(path.clone())?),
+        _ => None,
+    };
+    let mut failed = 0;
+    for entry in &entries {
+        if dry_run {
+            println!("Would restore document {} (run {} at {}): {}", entry.document_id, entry.run_id, entry.applied_at, entry.previous);
+            continue;
+        }
+        let Some(previous) = entry.previous.as_object() else {
+            slog_scope::error!("Journal entry {} of document {} is invalid", entry.id, entry.document_id);
+            failed += 1;
+            continue;
+        };
+        if let Err(e) = patch_document(client, &config.base_url, entry.document_id, previous).await {
+            slog_scope::error!("Could not restore document {}: {}", entry.document_id, e);
+            failed += 1;
+            continue;
+        }
+        state.mark_rolled_back(entry.id)?;
+        if let Some(store) = &local_store {
+            store.remove(entry.document_id)?;
+        }
+        slog_scope::info!("Restored document {} to its state before run {}", entry.document_id, entry.run_id);
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(Box::new(ResponseError::Other(format!("{} of {} changes could not be rolled back", failed, entries.len())))),
+    }
+}
+
+pub fn open_state(config: &Config) -> Result<StateStore, Box<dyn std::error::Error>> {
+    let path = config
+        .state_db
+        .as_ref()
+        .ok_or_else(|| ResponseError::Other("state.path is not set, set DOCLYTICS_STATE_DB".to_string()))?;
+    StateStore::open(path)
+}
@@ -0,0 +1,580 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::Mode;
+use crate::chunking::Chunking;
+use crate::language::PROMPT_LANGUAGES;
+use crate::cli::GlobalOptions;
+use crate::field_values::parse_field_type_overrides;
+use crate::marker::MarkerStrategy;
+use crate::paperless::{DefaultFieldOptions, MergeStrategy};
+use crate::prompt::{PromptProfile, PromptTemplate};
+use crate::review::ReviewRules;
+
+/// Every setting with its key in the configuration file and its environment
+/// variable. Environment variables take precedence over the file.
+const SETTINGS: [(&str, &str); 44] = [
+    ("paperless.base_url", "PAPERLESS_BASE_URL"),
+    ("paperless.token", "PAPERLESS_TOKEN"),
+    ("paperless.filter", "PAPERLESS_FILTER"),
+    ("paperless.marker", "DOCLYTICS_MARKER"),
+    ("llm.backend", "LLM_BACKEND"),
+    ("llm.context_size", "LLM_CONTEXT_SIZE"),
+    ("llm.max_pages", "LLM_MAX_PAGES"),
+    ("ollama.host", "OLLAMA_HOST"),
+    ("ollama.port", "OLLAMA_PORT"),
+    ("ollama.secure_endpoint", "OLLAMA_SECURE_ENDPOINT"),
+    ("ollama.model", "OLLAMA_MODEL"),
+    ("ollama.structured_output", "OLLAMA_STRUCTURED_OUTPUT"),
+    ("openai.base_url", "OPENAI_BASE_URL"),
+    ("openai.api_key", "OPENAI_API_KEY"),
+    ("openai.model", "OPENAI_MODEL"),
+    ("openai.temperature", "OPENAI_TEMPERATURE"),
+    ("openai.max_tokens", "OPENAI_MAX_TOKENS"),
+    ("prompt.language", "LANGUAGE"),
+    ("prompt.language_field", "LANGUAGE_FIELD"),
+    ("prompt.base_prompt", "BASE_PROMPT"),
+    ("prompt.template_file", "PROMPT_TEMPLATE_FILE"),
+    ("custom_fields.mode", "MODE"),
+    ("custom_fields.field_types", "DOCLYTICS_FIELD_TYPES"),
+    ("custom_fields.descriptions", "DOCLYTICS_FIELD_DESCRIPTIONS"),
+    ("tags.mode", "DOCLYTICS_TAGS"),
+    ("tags.merge", "DOCLYTICS_TAGS_MERGE"),
+    ("document_type.mode", "DOCLYTICS_DOCTYPE"),
+    ("document_type.merge", "DOCLYTICS_DOCTYPE_MERGE"),
+    ("correspondent.mode", "DOCLYTICS_CORRESPONDENT"),
+    ("correspondent.merge", "DOCLYTICS_CORRESPONDENT_MERGE"),
+    ("dry_run", "DRY_RUN"),
+    ("daemon.interval", "DAEMON_INTERVAL"),
+    ("daemon.max_backoff", "DAEMON_MAX_BACKOFF"),
+    ("webhook.listen", "WEBHOOK_LISTEN"),
+    ("webhook.secret", "WEBHOOK_SECRET"),
+    ("webhook.dedup_window", "WEBHOOK_DEDUP_WINDOW"),
+    ("concurrency.llm", "LLM_CONCURRENCY"),
+    ("concurrency.paperless", "PAPERLESS_CONCURRENCY"),
+    ("state.path", "DOCLYTICS_STATE_DB"),
+    ("reprocess.filter", "REPROCESS_FILTER"),
+    ("reprocess.max_documents", "REPROCESS_MAX_DOCUMENTS"),
+    ("review.all", "REVIEW_ALL"),
+    ("review.tags", "REVIEW_TAGS"),
+    ("review.document_types", "REVIEW_DOCUMENT_TYPES"),
+];
+
+/// Used when neither `--config` nor `DOCLYTICS_CONFIG` is set and the file exists.
+const DEFAULT_CONFIG_FILE: &str = "doclytics.toml";
+
+#[derive(Debug, Clone)]
+pub enum LlmConfig {
+    Ollama {
+        host: String,
+        port: u16,
+        secure_endpoint: bool,
+        model: String,
+        structured_output: bool,
+    },
+    OpenAi {
+        base_url: String,
+        api_key: Option<String>,
+        model: String,
+        temperature: Option<f32>,
+        max_tokens: Option<u32>,
+    },
+}
+
+/// The validated settings of doclytics.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub base_url: String,
+    pub token: String,
+    pub filter: String,
+    pub marker: MarkerStrategy,
+    pub llm: LlmConfig,
+    /// How long documents are split for the model
+    pub chunking: Chunking,
+    /// Language of the built-in prompt, `AUTO` to detect it per document
+    pub language: String,
+    /// Custom field the detected language is stored in
+    pub language_field: Option<String>,
+    /// `BASE_PROMPT` or the content of `PROMPT_TEMPLATE_FILE`, the built-in
+    /// prompt for `language` if not set
+    pub prompt_template: Option<PromptTemplate>,
+    /// Prompts for some documents, the first matching profile is used
+    pub profiles: Vec<PromptProfile>,
+    pub mode: Mode,
+    pub field_types: HashMap<String, String>,
+    /// Description of a custom field for the generated prompt, by field name
+    pub field_descriptions: HashMap<String, String>,
+    pub tags: DefaultFieldOptions,
+    pub document_type: DefaultFieldOptions,
+    pub correspondent: DefaultFieldOptions,
+    pub dry_run: bool,
+    /// Time between two polls in daemon mode
+    pub daemon_interval: Duration,
+    /// Upper limit of the retry delay while Paperless or the LLM is unreachable
+    pub daemon_max_backoff: Duration,
+    /// Address the webhook server listens on
+    pub webhook_listen: SocketAddr,
+    /// Shared secret webhook requests have to send, required by `serve`
+    pub webhook_secret: Option<String>,
+    /// Notifications for the same document within this window are ignored
+    pub webhook_dedup_window: Duration,
+    /// Maximum number of requests sent to the LLM at the same time
+    pub llm_concurrency: usize,
+    /// Maximum number of requests sent to Paperless at the same time
+    pub paperless_concurrency: usize,
+    /// SQLite database recording every result, disabled if not set
+    pub state_db: Option<PathBuf>,
+    /// Query selecting the documents `reprocess-stale` checks, all documents if empty
+    pub reprocess_filter: String,
+    /// Maximum number of documents `reprocess-stale` processes in one run
+    pub reprocess_max_documents: Option<usize>,
+    /// Documents whose changes are queued for approval instead of applied
+    pub review: ReviewRules,
+}
+
+/// All problems found in the configuration, so they can be fixed at once.
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for error in &self.0 {
+            writeln!(f, "  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Raw setting values together with where they came from.
+struct Settings {
+    values: HashMap<&'static str, (String, String)>,
+    /// Only configurable in the file
+    profiles: Vec<PromptProfile>,
+    errors: Vec<String>,
+}
+
+impl Config {
+    /// Loads the configuration file given by `--config`, `DOCLYTICS_CONFIG`
+    /// or `doclytics.toml`, applies environment variables and command line
+    /// options and validates the result.
+    pub fn load(options: &GlobalOptions) -> Result<Config, ConfigError> {
+        let path = options
+            .config
+            .clone()
+            .or_else(|| std::env::var("DOCLYTICS_CONFIG").ok().map(PathBuf::from));
+        let file = match &path {
+            Some(path) => Some(read_file(path)?),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Some(read_file(Path::new(DEFAULT_CONFIG_FILE))?),
+            None => None,
+        };
+        Config::from_sources(file.as_ref().map(|(path, content)| (path.as_str(), content.as_str())), |key| std::env::var(key).ok(), options)
+    }
+
+    fn from_sources(
+        file: Option<(&str, &str)>,
+        env: impl Fn(&str) -> Option<String>,
+        options: &GlobalOptions,
+    ) -> Result<Config, ConfigError> {
+        let mut settings = Settings { values: HashMap::new(), profiles: Vec::new(), errors: Vec::new() };
+        if let Some((path, content)) = file {
+            settings.read_file(path, content);
+        }
+        for (key, env_key) in SETTINGS {
+            if let Some(value) = env(env_key) {
+                settings.values.insert(key, (value, env_key.to_string()));
+            }
+        }
+        let cli = [
+            ("paperless.base_url", "--base-url", options.base_url.clone()),
+            ("paperless.token", "--token", options.token.clone()),
+            ("paperless.filter", "--filter", options.filter.clone()),
+            ("paperless.marker", "--marker", options.marker.clone()),
+            ("llm.backend", "--llm-backend", options.llm_backend.clone()),
+            ("dry_run", "--dry-run", options.dry_run.then(|| "true".to_string())),
+        ];
+        for (key, flag, value) in cli {
+            if let Some(value) = value {
+                settings.values.insert(key, (value, flag.to_string()));
+            }
+        }
+        settings.build(options.model.clone())
+    }
+}
+
+fn read_file(path: &Path) -> Result<(String, String), ConfigError> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => Ok((path.display().to_string(), content)),
+        Err(e) => Err(ConfigError(vec![format!("could not read {}: {}", path.display(), e)])),
+    }
+}
+
+impl Settings {
+    fn read_file(&mut self, path: &str, content: &str) {
+        let table: toml::Table = match toml::from_str(content) {
+            Ok(table) => table,
+            Err(e) => return self.errors.push(format!("{}: {}", path, e)),
+        };
+        let mut entries = Vec::new();
+        for (key, value) in table {
+            match value {
+                toml::Value::Array(profiles) if key == "profile" => {
+                    for profile in profiles {
+                        match PromptProfile::from_toml(profile) {
+                            Ok(profile) => self.profiles.push(profile),
+                            Err(e) => self.errors.push(format!("{}: {}", path, e)),
+                        }
+                    }
+                }
+                toml::Value::Table(section) => entries.extend(section.into_iter().map(|(name, value)| (format!("{}.{}", key, name), value))),
+                value => entries.push((key, value)),
+            }
+        }
+        for (key, value) in entries {
+            let Some((key, _)) = SETTINGS.iter().find(|(name, _)| *name == key) else {
+                self.errors.push(format!("{}: unknown setting {}", path, key));
+                continue;
+            };
+            let value = match value {
+                toml::Value::String(value) => value,
+                // Field types and descriptions can also be written as a table
+                // of name = value, descriptions may contain commas
+                toml::Value::Table(entries) => entries
+                    .into_iter()
+                    .map(|(name, value)| match value {
+                        toml::Value::String(value) => format!("{}={}", name, value),
+                        value => format!("{}={}", name, value),
+                    })
+                    .collect::<Vec<String>>()
+                    .join(if *key == "custom_fields.descriptions" { ";" } else { "," }),
+                // Lists like review.tags can also be written as an array
+                toml::Value::Array(items) => match join_array(items) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        self.errors.push(format!("{}: {}: {}", path, key, e));
+                        continue;
+                    }
+                },
+                value => value.to_string(),
+            };
+            self.values.insert(key, (value, format!("{} in {}", key, path)));
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|(value, _)| value.as_str())
+    }
+
+    /// Parses the setting, recording an error naming its source if it is
+    /// invalid.
+    fn parse<T>(&mut self, key: &str, parse: impl Fn(&str) -> Result<T, String>) -> Option<T> {
+        let (value, source) = self.values.get(key)?;
+        match parse(value) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.errors.push(format!("{}: {}", source, e));
+                None
+            }
+        }
+    }
+
+    fn required(&mut self, key: &str) -> String {
+        match self.get(key).filter(|value| !value.is_empty()) {
+            Some(value) => value.to_string(),
+            None => {
+                let env_key = SETTINGS.iter().find(|(name, _)| *name == key).map_or(key, |(_, env_key)| env_key);
+                self.errors.push(format!("{} is required, set {} or {} in the configuration file", key, env_key, key));
+                String::new()
+            }
+        }
+    }
+
+    fn default_field_options(&mut self, section: &str, default_merge: MergeStrategy) -> DefaultFieldOptions {
+        DefaultFieldOptions {
+            mode: self.parse(&format!("{}.mode", section), parse_mode).unwrap_or(Mode::NoCreate),
+            merge: self.parse(&format!("{}.merge", section), parse_merge).unwrap_or(default_merge),
+        }
+    }
+
+    fn prompt_template(&mut self) -> Option<PromptTemplate> {
+        if self.get("prompt.base_prompt").is_some() && self.get("prompt.template_file").is_some() {
+            self.errors.push("prompt.base_prompt and prompt.template_file cannot both be set".to_string());
+            return None;
+        }
+        if let Some(prompt) = self.parse("prompt.base_prompt", PromptTemplate::parse) {
+            return Some(prompt);
+        }
+        self.parse("prompt.template_file", |path| {
+            let source = std::fs::read_to_string(path).map_err(|e| format!("could not read {}: {}", path, e))?;
+            PromptTemplate::parse(&source).map_err(|e| format!("{}: {}", path, e))
+        })
+    }
+
+    fn build(mut self, model: Option<String>) -> Result<Config, ConfigError> {
+        let base_url = self.required("paperless.base_url");
+        if !base_url.is_empty() && !base_url.starts_with("http://") && !base_url.starts_with("https://") {
+            self.errors.push(format!("paperless.base_url: '{}' must start with http:// or https://", base_url));
+        }
+        let token = self.required("paperless.token");
+        let marker = self
+            .parse("paperless.marker", MarkerStrategy::parse)
+            .unwrap_or_else(|| MarkerStrategy::CustomField("tagged".to_string()));
+        let filter = self.get("paperless.filter").map_or_else(|| marker.default_filter(), str::to_string);
+
+        let llm = match self.get("llm.backend").unwrap_or("ollama").to_lowercase().as_ref() {
+            "ollama" => LlmConfig::Ollama {
+                host: self.get("ollama.host").unwrap_or("localhost").to_string(),
+                port: self.parse("ollama.port", |v| v.parse::<u16>().map_err(|_| format!("'{}' is not a valid port", v))).unwrap_or(11434),
+                secure_endpoint: self.parse("ollama.secure_endpoint", parse_bool).unwrap_or(false),
+                model: model.unwrap_or_else(|| self.get("ollama.model").unwrap_or("llama2:13b").to_string()),
+                structured_output: self.parse("ollama.structured_output", parse_bool).unwrap_or(true),
+            },
+            "openai" => LlmConfig::OpenAi {
+                base_url: self.get("openai.base_url").unwrap_or("http://localhost:8000/v1").to_string(),
+                api_key: self.get("openai.api_key").map(str::to_string),
+                model: model.unwrap_or_else(|| self.required("openai.model")),
+                temperature: self.parse("openai.temperature", |v| match v.parse::<f32>() {
+                    Ok(t) if (0.0..=2.0).contains(&t) => Ok(t),
+                    _ => Err(format!("'{}' is not a temperature between 0 and 2", v)),
+                }),
+                max_tokens: self.parse("openai.max_tokens", |v| v.parse::<u32>().map_err(|_| format!("'{}' is not a number", v))),
+            },
+            other => {
+                self.errors.push(format!("llm.backend: unknown backend '{}', expected ollama or openai", other));
+                LlmConfig::Ollama { host: String::new(), port: 0, secure_endpoint: false, model: String::new(), structured_output: false }
+            }
+        };
+
+        let model = match &llm {
+            LlmConfig::Ollama { model, .. } | LlmConfig::OpenAi { model, .. } => model.clone(),
+        };
+        let chunking = Chunking {
+            context_size: self.parse("llm.context_size", |v| parse_context_size(v, &model)).flatten(),
+            max_pages: self.parse("llm.max_pages", parse_limit),
+        };
+
+        let language = self
+            .parse("prompt.language", |v| match v.to_uppercase() {
+                language if language == "AUTO" || PROMPT_LANGUAGES.contains(&language.as_str()) => Ok(language),
+                _ => Err(format!("unsupported language '{}', expected auto or one of {}", v, PROMPT_LANGUAGES.join(", "))),
+            })
+            .unwrap_or_else(|| "EN".to_string());
+        let language_field = self.get("prompt.language_field").filter(|field| !field.is_empty()).map(str::to_string);
+        let prompt_template = self.prompt_template();
+        let mode = self.parse("custom_fields.mode", parse_mode).unwrap_or(Mode::NoAnalyze);
+        let field_types = self.parse("custom_fields.field_types", parse_field_type_overrides).unwrap_or_default();
+        let field_descriptions = self.parse("custom_fields.descriptions", parse_descriptions).unwrap_or_default();
+        let tags = self.default_field_options("tags", MergeStrategy::Union);
+        let document_type = self.default_field_options("document_type", MergeStrategy::Overwrite);
+        let correspondent = self.default_field_options("correspondent", MergeStrategy::Overwrite);
+        let dry_run = self.parse("dry_run", parse_bool).unwrap_or(false);
+        let daemon_interval = self.parse("daemon.interval", parse_seconds).unwrap_or(Duration::from_secs(300));
+        let daemon_max_backoff = self.parse("daemon.max_backoff", parse_seconds).unwrap_or(Duration::from_secs(3600));
+        let webhook_listen = self
+            .parse("webhook.listen", |v| v.parse::<SocketAddr>().map_err(|_| format!("'{}' is not a valid address, e.g. 0.0.0.0:8080", v)))
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 8080)));
+        let webhook_secret = self.get("webhook.secret").filter(|secret| !secret.is_empty()).map(str::to_string);
+        let webhook_dedup_window = self.parse("webhook.dedup_window", parse_seconds).unwrap_or(Duration::from_secs(300));
+        let llm_concurrency = self.parse("concurrency.llm", parse_limit).unwrap_or(1);
+        let paperless_concurrency = self.parse("concurrency.paperless", parse_limit).unwrap_or(4);
+        let state_db = self.get("state.path").filter(|path| !path.is_empty()).map(PathBuf::from);
+        let reprocess_filter = self.get("reprocess.filter").unwrap_or_default().to_string();
+        let reprocess_max_documents = self.parse("reprocess.max_documents", parse_limit);
+        let review = ReviewRules {
+            all: self.parse("review.all", parse_bool).unwrap_or(false),
+            tags: self.get("review.tags").map(parse_list).unwrap_or_default(),
+            document_types: self.get("review.document_types").map(parse_list).unwrap_or_default(),
+        };
+        if review.is_enabled() && state_db.is_none() {
+            self.errors.push("review needs the state database, set DOCLYTICS_STATE_DB or state.path".to_string());
+        }
+
+        if !self.errors.is_empty() {
+            return Err(ConfigError(self.errors));
+        }
+        Ok(Config {
+            base_url,
+            token,
+            filter,
+            marker,
+            llm,
+            chunking,
+            language,
+            language_field,
+            prompt_template,
+            profiles: self.profiles,
+            mode,
+            field_types,
+            field_descriptions,
+            tags,
+            document_type,
+            correspondent,
+            dry_run,
+            daemon_interval,
+            daemon_max_backoff,
+            webhook_listen,
+            webhook_secret,
+            webhook_dedup_window,
+            llm_concurrency,
+            paperless_concurrency,
+            state_db,
+            reprocess_filter,
+            reprocess_max_documents,
+            review,
+        })
+    }
+}
+
+/// Parses a token count, or `model=tokens` pairs of which the entry for
+/// `model` is used.
+fn parse_context_size(value: &str, model: &str) -> Result<Option<usize>, String> {
+    if !value.contains('=') {
+        return parse_limit(value).map(Some);
+    }
+    for entry in value.split(',').filter(|entry| !entry.trim().is_empty()) {
+        let (name, size) = entry.split_once('=').ok_or_else(|| format!("expected model=tokens, got '{}'", entry.trim()))?;
+        let size = parse_limit(size.trim())?;
+        if name.trim() == model {
+            return Ok(Some(size));
+        }
+    }
+    Ok(None)
+}
+
+fn parse_limit(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(limit) if limit > 0 => Ok(limit),
+        _ => Err(format!("'{}' is not a positive number", value)),
+    }
+}
+
+/// Joins the items of an array to a comma separated list.
+fn join_array(items: Vec<toml::Value>) -> Result<String, String> {
+    items
+        .into_iter()
+        .map(|item| match item {
+            toml::Value::String(item) if item.contains(',') => Err(format!("list items cannot contain commas, got '{}'", item)),
+            toml::Value::String(item) => Ok(item),
+            toml::Value::Array(_) | toml::Value::Table(_) => Err(format!("expected a list of values, got {}", item)),
+            item => Ok(item.to_string()),
+        })
+        .collect::<Result<Vec<String>, String>>()
+        .map(|items| items.join(","))
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(str::to_string).collect()
+}
+
+/// Parses a `name=description` list separated by semicolons.
+fn parse_descriptions(value: &str) -> Result<HashMap<String, String>, String> {
+    value
+        .split(';')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| match entry.split_once('=') {
+            Some((name, description)) => Ok((name.trim().to_string(), description.trim().to_string())),
+            None => Err(format!("expected name=description, got '{}'", entry.trim())),
+        })
+        .collect()
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_lowercase().as_ref() {
+        "true" | "1" | "yes" => Ok(true),
+        "false" | "0" | "no" => Ok(false),
+        _ => Err(format!("'{}' is not a boolean, expected true or false", value)),
+    }
+}
+
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    match value.parse::<u64>() {
+        Ok(seconds) if seconds > 0 => Ok(Duration::from_secs(seconds)),
+        _ => Err(format!("'{}' is not a positive number of seconds", value)),
+    }
+}
+
+fn parse_mode(value: &str) -> Result<Mode, String> {
+    Mode::parse(value).ok_or_else(|| format!("unknown mode '{}', expected 0 (no_analyze), 1 (no_create) or 2 (create)", value))
+}
+
+fn parse_merge(value: &str) -> Result<MergeStrategy, String> {
+    MergeStrategy::parse(value).ok_or_else(|| format!("unknown merge strategy '{}', expected union, if_empty or overwrite", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use crate::cli::Cli;
+
+    fn load(file: &str, env: &[(&str, &str)], args: &[&str]) -> Result<Config, ConfigError> {
+        let cli = Cli::try_parse_from([&["doclytics"], args].concat()).unwrap();
+        let env: HashMap<String, String> = env.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Config::from_sources(Some(("doclytics.toml", file)), |key| env.get(key).cloned(), &cli.options)
+    }
+
+    const FILE: &str = r#"
+        dry_run = false
+
+        [paperless]
+        base_url = "http://paperless:8000"
+        token = "secret"
+        marker = "tag:doclytics"
+
+        [llm]
+        backend = "openai"
+        context_size = { "qwen2.5" = 32768, llama3 = 8192 }
+
+        [openai]
+        model = "qwen2.5"
+        temperature = 0.2
+
+        [custom_fields]
+        mode = 2
+        field_types = { date_received = "date" }
+        descriptions = { date_received = "the date the letter arrived, not the date it was written", total = "gross amount" }
+
+        [tags]
+        merge = "if_empty"
+
+        [state]
+        path = "doclytics.db"
+
+        [review]
+        tags = ["Contract", "Tax"]
+    "#;
+
+    #[test]
+    fn test_load_config() {
+        let config = load(FILE, &[("OPENAI_MODEL", "llama3"), ("MODE", "no_create")], &["--filter", "tag:inbox"]).unwrap();
+        assert_eq!(config.base_url, "http://paperless:8000");
+        assert_eq!(config.marker, MarkerStrategy::Tag("doclytics".to_string()));
+        assert_eq!(config.filter, "tag:inbox");
+        assert!(matches!(config.llm, LlmConfig::OpenAi { ref model, temperature: Some(t), .. } if model == "llama3" && t == 0.2));
+        assert!(matches!(config.mode, Mode::NoCreate));
+        assert_eq!(config.field_types.get("date_received").map(String::as_str), Some("date"));
+        assert_eq!(config.field_descriptions.get("date_received").map(String::as_str), Some("the date the letter arrived, not the date it was written"));
+        assert_eq!(config.field_descriptions.len(), 2);
+        assert_eq!(config.tags.merge, MergeStrategy::IfEmpty);
+        assert_eq!(config.chunking.context_size, Some(8192));
+        assert_eq!(config.document_type.merge, MergeStrategy::Overwrite);
+        assert_eq!(config.review.tags, vec!["Contract", "Tax"]);
+    }
+
+    #[test]
+    fn test_invalid_config_reports_every_error() {
+        let errors = load("[paperless]\nbase_url = \"paperless:8000\"\ntokn = \"x\"\n[review]\ntags = [\"Tax, 2024\"]", &[("MODE", "3"), ("DOCLYTICS_TAGS_MERGE", "unoin")], &[]).unwrap_err().0;
+        assert_eq!(errors, vec![
+            "doclytics.toml: unknown setting paperless.tokn",
+            "doclytics.toml: review.tags: list items cannot contain commas, got 'Tax, 2024'",
+            "paperless.base_url: 'paperless:8000' must start with http:// or https://",
+            "paperless.token is required, set PAPERLESS_TOKEN or paperless.token in the configuration file",
+            "MODE: unknown mode '3', expected 0 (no_analyze), 1 (no_create) or 2 (create)",
+            "DOCLYTICS_TAGS_MERGE: unknown merge strategy 'unoin', expected union, if_empty or overwrite",
+        ]);
+    }
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+use reqwest::Client;
+use crate::{process_documents, ProcessingContext, Reprocess};
+use crate::config::Config;
+use crate::llm_api::LlmBackend;
+use crate::paperless::query_custom_fields;
+use crate::shutdown::Shutdown;
+
+/// First retry delay after Paperless or the LLM could not be reached.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Processes the documents matching the filter every `daemon_interval`
+/// until a shutdown is requested.
+pub async fn run(client: &Client, llm: &dyn LlmBackend, config: &Config, shutdown: Shutdown) -> Result<(), Box<dyn std::error::Error>> {
+    slog_scope::info!("Starting daemon, polling every {}s", config.daemon_interval.as_secs());
+    let mut failures = 0;
+    while !shutdown.is_requested() {
+        let delay = match poll(client, llm, config, &shutdown).await {
+            Ok(()) => {
+                failures = 0;
+                config.daemon_interval
+            }
+            Err(e) => {
+                failures += 1;
+                let delay = backoff_delay(failures, config.daemon_max_backoff);
+                slog_scope::warn!("Poll failed ({} in a row), retrying in {}s: {}", failures, delay.as_secs(), e);
+                delay
+            }
+        };
+        if !shutdown.sleep(delay).await {
+            break;
+        }
+    }
+    slog_scope::info!("Daemon stopped");
+    Ok(())
+}
+
+/// One pass over the documents. Fails without touching any document if
+/// Paperless or the LLM is unreachable.
+async fn poll(client: &Client, llm: &dyn LlmBackend, config: &Config, shutdown: &Shutdown) -> Result<(), Box<dyn std::error::Error>> {
+    llm.check().await.map_err(|e| e as Box<dyn std::error::Error>)?;
+    // Fields are fetched on every poll to pick up fields added in the meantime
+    let mut fields = query_custom_fields(client, &config.base_url).await?;
+    let context = ProcessingContext::new(client, llm, config, &mut fields, config.dry_run, Reprocess::Never, shutdown.clone()).await?;
+    process_documents(&context, &mut fields, &config.filter).await
+}
+
+/// Doubles the delay with every failure, up to `max`.
+fn backoff_delay(failures: u32, max: Duration) -> Duration {
+    let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+    INITIAL_BACKOFF.saturating_mul(factor).min(max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_delay() {
+        let max = Duration::from_secs(600);
+        assert_eq!(backoff_delay(1, max), Duration::from_secs(10));
+        assert_eq!(backoff_delay(3, max), Duration::from_secs(40));
+        assert_eq!(backoff_delay(7, max), max);
+        assert_eq!(backoff_delay(64, max), max);
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use reqwest::Client;
+use tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use crate::{Document, Field, Mode};
+use crate::field_values::{coerce_value, infer_data_type};
+use crate::marker::Marker;
+use crate::state::Journal;
+use crate::paperless::{create_custom_field, find_or_create_named_object, patch_document, CreateField, DefaultField, MergeStrategy, PaperlessDefaultFieldType};
+
+/// A tag, document type or correspondent. Objects without id do not exist in
+/// paperless yet and are created when the update is applied.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ObjectRef {
+    pub id: Option<u32>,
+    pub name: String,
+}
+
+/// A custom field value. Fields without id are created when the update is
+/// applied.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CustomFieldChange {
+    pub field: Option<u32>,
+    pub name: String,
+    pub data_type: String,
+    pub value: Value,
+}
+
+/// Every change doclytics proposes for a single document. It is applied with
+/// one request, so a document is never left half updated.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DocumentUpdate {
+    pub document_id: u32,
+    pub title: Option<String>,
+    pub custom_fields: Vec<CustomFieldChange>,
+    pub tags: Option<Vec<ObjectRef>>,
+    pub document_type: Option<ObjectRef>,
+    pub correspondent: Option<ObjectRef>,
+}
+
+impl DocumentUpdate {
+    pub fn new(document_id: u32) -> Self {
+        DocumentUpdate {
+            document_id,
+            ..Default::default()
+        }
+    }
+
+    /// Adds the title and custom field values extracted by the LLM. Values
+    /// are converted to the data type of their field, unknown keys become new
+    /// fields in `Mode::Create`.
+    pub fn add_custom_fields(
+        &mut self,
+        fields: &[Field],
+        metadata: &HashMap<String, Option<Value>>,
+        mode: Mode,
+        marker_field: Option<u32>,
+        field_type_overrides: &HashMap<String, String>,
+    ) {
+        for (key, value) in metadata {
+            let value = value.as_ref().unwrap_or(&Value::Null);
+            if key == "title" {
+                self.title = value.as_str().map(|title| title.to_string());
+                continue;
+            }
+
+            let field = match fields.iter().find(|&f| f.name == *key) {
+                Some(field) if Some(field.id) == marker_field => continue,
+                Some(field) => field.clone(),
+                None if matches!(mode, Mode::Create) => Field {
+                    id: 0,
+                    name: key.clone(),
+                    data_type: infer_data_type(key, value, field_type_overrides),
+                    extra_data: None,
+                },
+                None => continue,
+            };
+            match coerce_value(&field, value) {
+                Ok(value) => self.custom_fields.push(CustomFieldChange {
+                    field: (field.id != 0).then_some(field.id),
+                    name: field.name,
+                    data_type: field.data_type,
+                    value,
+                }),
+                Err(reason) => {
+                    slog_scope::warn!("Skipping field {} for document {}: {}", field.name, self.document_id, reason);
+                }
+            }
+        }
+    }
+
+    /// Combines the objects suggested by the LLM with the current values of
+    /// the document according to `merge`. The current tags are named after
+    /// the `known` objects.
+    pub fn merge_default_field(
+        &mut self,
+        document: &Document,
+        field_type: PaperlessDefaultFieldType,
+        merge: MergeStrategy,
+        suggested: Vec<ObjectRef>,
+        known: &[DefaultField],
+    ) {
+        match field_type {
+            PaperlessDefaultFieldType::Tag => {
+                let name = |id: u32| known.iter().find(|object| object.id == Some(id)).map_or_else(|| format!("#{}", id), |object| object.name.clone());
+                let existing: Vec<ObjectRef> = document.tags.iter().map(|id| ObjectRef { id: Some(*id), name: name(*id) }).collect();
+                let tags = match merge {
+                    MergeStrategy::Union => {
+                        let mut tags = existing;
+                        for tag in suggested {
+                            if tag.id.is_none_or(|id| !document.tags.contains(&id)) {
+                                tags.push(tag);
+                            }
+                        }
+                        tags
+                    }
+                    MergeStrategy::IfEmpty if !document.tags.is_empty() => return,
+                    MergeStrategy::IfEmpty | MergeStrategy::Overwrite => suggested,
+                };
+                let ids: Vec<Option<u32>> = tags.iter().map(|tag| tag.id).collect();
+                let unchanged = ids.len() == document.tags.len() && ids.iter().zip(&document.tags).all(|(a, b)| *a == Some(*b));
+                if !unchanged {
+                    self.tags = Some(tags);
+                }
+            }
+            PaperlessDefaultFieldType::DocumentType | PaperlessDefaultFieldType::Correspondent => {
+                let existing = match field_type {
+                    PaperlessDefaultFieldType::DocumentType => document.document_type,
+                    _ => document.correspondent,
+                };
+                let Some(object) = suggested.into_iter().next() else {
+                    return;
+                };
+                let value = match merge {
+                    MergeStrategy::Union | MergeStrategy::IfEmpty if existing.is_some() => None,
+                    _ if existing.is_some() && existing == object.id => None,
+                    _ => Some(object),
+                };
+                match field_type {
+                    PaperlessDefaultFieldType::DocumentType => self.document_type = value,
+                    _ => self.correspondent = value,
+                }
+            }
+        }
+    }
+
+    /// Builds the PATCH body. Existing custom field values of the document
+    /// are kept, since paperless replaces the whole list. The marker field or
+    /// tag is part of the same request.
+    pub fn to_payload(&self, document: &Document, marker: &Marker) -> Map<String, Value> {
+        let mut payload = Map::new();
+        if let Some(title) = self.title.as_ref().filter(|title| **title != document.title) {
+            payload.insert("title".to_string(), json!(title));
+        }
+
+        let marker_field = marker.field_id();
+        if !self.custom_fields.is_empty() || marker_field.is_some() {
+            let mut values: Vec<(u32, Value)> = document
+                .custom_fields
+                .iter()
+                .map(|f| (f.field, f.value.clone().unwrap_or(Value::Null)))
+                .collect();
+            let changes = self.custom_fields.iter().filter_map(|f| f.field.map(|id| (id, f.value.clone())));
+            for (id, value) in changes.chain(marker_field.map(|id| (id, json!(true)))) {
+                match values.iter_mut().find(|(field, _)| *field == id) {
+                    Some(existing) => existing.1 = value,
+                    None => values.push((id, value)),
+                }
+            }
+            let custom_fields: Vec<Value> = values.into_iter().map(|(field, value)| json!({ "field": field, "value": value })).collect();
+            payload.insert("custom_fields".to_string(), json!(custom_fields));
+        }
+
+        let marker_tag = marker.tag_id();
+        if self.tags.is_some() || marker_tag.is_some() {
+            let mut tags: Vec<u32> = match &self.tags {
+                Some(tags) => tags.iter().filter_map(|tag| tag.id).collect(),
+                None => document.tags.clone(),
+            };
+            if let Some(tag_id) = marker_tag.filter(|id| !tags.contains(id)) {
+                tags.push(tag_id);
+            }
+            if self.tags.is_some() || tags != document.tags {
+                payload.insert("tags".to_string(), json!(tags));
+            }
+        }
+
+        if let Some(id) = self.document_type.as_ref().and_then(|object| object.id) {
+            payload.insert("document_type".to_string(), json!(id));
+        }
+        if let Some(id) = self.correspondent.as_ref().and_then(|object| object.id) {
+            payload.insert("correspondent".to_string(), json!(id));
+        }
+        payload
+    }
+
+    /// Creates missing fields and objects and sends all changes in a single
+    /// PATCH. The previous values are saved to the journal first. The
+    /// document is only marked as processed if it succeeded.
+    pub async fn apply(
+        &mut self,
+        client: &Client,
+        base_url: &str,
+        document: &Document,
+        fields: &Mutex<Vec<Field>>,
+        marker: &Marker,
+        journal: Option<&Journal<'_>>,
+    ) -> Result<Map<String, Value>, Box<dyn std::error::Error>> {
+        self.create_missing(client, base_url, fields).await?;
+
+        let payload = self.to_payload(document, marker);
+        if payload.is_empty() {
+            slog_scope::info!("No changes for document {}", document.id);
+        } else {
+            let entry = match journal {
+                Some(journal) => Some(journal.record(document.id, &previous_values(document, &payload), &payload)?),
+                None => None,
+            };
+            if let Err(e) = patch_document(client, base_url, document.id, &payload).await {
+                if let Some((journal, entry)) = journal.zip(entry) {
+                    if let Err(e) = journal.discard(entry) {
+                        slog_scope::warn!("Could not discard journal entry {}: {}", entry, e);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        marker.mark(document.id)?;
+        Ok(payload)
+    }
+
+    /// Documents processed concurrently create their fields and objects one
+    /// after another, so each is only created once.
+    async fn create_missing(&mut self, client: &Client, base_url: &str, fields: &Mutex<Vec<Field>>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut fields = fields.lock().await;
+        for change in self.custom_fields.iter_mut().filter(|f| f.field.is_none()) {
+            // Another document may have created the field in the meantime
+            if let Some(field) = fields.iter().find(|f| f.name == change.name) {
+                change.field = Some(field.id);
+                continue;
+            }
+            slog_scope::info!("Creating field: {} of type {}", change.name, change.data_type);
+            let create_field = CreateField {
+                name: change.name.clone(),
+                data_type: change.data_type.clone(),
+                default_value: None,
+            };
+            let field = create_custom_field(client, &create_field, base_url).await?;
+            change.field = Some(field.id);
+            // Later documents reuse the field instead of creating it again
+            fields.push(field);
+        }
+
+        let objects = self
+            .tags
+            .iter_mut()
+            .flatten()
+            .map(|tag| (PaperlessDefaultFieldType::Tag, tag))
+            .chain(self.document_type.iter_mut().map(|o| (PaperlessDefaultFieldType::DocumentType, o)))
+            .chain(self.correspondent.iter_mut().map(|o| (PaperlessDefaultFieldType::Correspondent, o)));
+        for (field_type, object) in objects.filter(|(_, object)| object.id.is_none()) {
+            object.id = Some(find_or_create_named_object(client, base_url, field_type, &object.name, 6).await?);
+        }
+        Ok(())
+    }
+}
+
+/// The values of the document `payload` overwrites, sending them restores
+/// the document.
+pub fn previous_values(document: &Document, payload: &Map<String, Value>) -> Map<String, Value> {
+    let mut previous = Map::new();
+    for key in payload.keys() {
+        let value = match key.as_str() {
+            "title" => json!(document.title),
+            "custom_fields" => json!(document.custom_fields),
+            "tags" => json!(document.tags),
+            "document_type" => json!(document.document_type),
+            "correspondent" => json!(document.correspondent),
+            _ => continue,
+        };
+        previous.insert(key.clone(), value);
+    }
+    previous
+}
+
+/// Whether the LLM has to be asked at all, a single value field that is
+/// already set is kept unless it should be overwritten.
+pub fn needs_default_field(document: &Document, field_type: PaperlessDefaultFieldType, merge: MergeStrategy) -> bool {
+    match (field_type, merge) {
+        (_, MergeStrategy::Overwrite) => true,
+        (PaperlessDefaultFieldType::Tag, MergeStrategy::Union) => true,
+        (PaperlessDefaultFieldType::Tag, MergeStrategy::IfEmpty) => document.tags.is_empty(),
+        (PaperlessDefaultFieldType::DocumentType, _) => document.document_type.is_none(),
+        (PaperlessDefaultFieldType::Correspondent, _) => document.correspondent.is_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::marker::LocalStore;
+    use crate::CustomField;
+    use crate::test_support::test_document;
+
+    fn document(tags: Vec<u32>, document_type: Option<u32>) -> Document {
+        Document {
+            title: "Invoice".to_string(),
+            document_type,
+            tags,
+            custom_fields: vec![
+                CustomField { field: 7, value: Some(json!("kept")) },
+                CustomField { field: 8, value: Some(json!("old")) },
+            ],
+            ..test_document(1)
+        }
+    }
+
+    fn existing(id: u32) -> ObjectRef {
+        ObjectRef { id: Some(id), name: id.to_string() }
+    }
+
+    fn tag_ids(update: &DocumentUpdate) -> Option<Vec<Option<u32>>> {
+        update.tags.as_ref().map(|tags| tags.iter().map(|t| t.id).collect())
+    }
+
+    #[test]
+    fn test_merge_tags() {
+        let doc = document(vec![1, 2], None);
+        let tag = PaperlessDefaultFieldType::Tag;
+        let new_tag = ObjectRef { id: None, name: "new".to_string() };
+        let known: Vec<DefaultField> = serde_json::from_value(json!([
+            { "id": 1, "slug": "inbox", "name": "Inbox", "matching_algorithm": 0 },
+            { "id": 2, "slug": "tax", "name": "Tax", "matching_algorithm": 6 }
+        ])).unwrap();
+
+        let mut update = DocumentUpdate::new(1);
+        update.merge_default_field(&doc, tag, MergeStrategy::Union, vec![existing(2), new_tag], &known);
+        assert_eq!(tag_ids(&update), Some(vec![Some(1), Some(2), None]));
+        let names: Vec<&str> = update.tags.iter().flatten().map(|tag| tag.name.as_str()).collect();
+        assert_eq!(names, vec!["Inbox", "Tax", "new"]);
+
+        let mut update = DocumentUpdate::new(1);
+        update.merge_default_field(&doc, tag, MergeStrategy::Union, vec![existing(1)], &known);
+        assert_eq!(tag_ids(&update), None);
+
+        let mut update = DocumentUpdate::new(1);
+        update.merge_default_field(&doc, tag, MergeStrategy::Overwrite, vec![existing(3)], &known);
+        assert_eq!(tag_ids(&update), Some(vec![Some(3)]));
+
+        let mut update = DocumentUpdate::new(1);
+        update.merge_default_field(&doc, tag, MergeStrategy::IfEmpty, vec![existing(3)], &known);
+        assert_eq!(tag_ids(&update), None);
+    }
+
+    #[test]
+    fn test_merge_document_type() {
+        let doctype = PaperlessDefaultFieldType::DocumentType;
+        let merged = |doc: Document, merge, suggested: Vec<ObjectRef>| {
+            let mut update = DocumentUpdate::new(1);
+            update.merge_default_field(&doc, doctype, merge, suggested, &[]);
+            update.document_type.and_then(|object| object.id)
+        };
+        assert_eq!(merged(document(vec![], None), MergeStrategy::IfEmpty, vec![existing(4), existing(5)]), Some(4));
+        assert_eq!(merged(document(vec![], Some(2)), MergeStrategy::IfEmpty, vec![existing(4)]), None);
+        assert_eq!(merged(document(vec![], Some(2)), MergeStrategy::Overwrite, vec![existing(4)]), Some(4));
+        assert_eq!(merged(document(vec![], Some(2)), MergeStrategy::Overwrite, vec![]), None);
+    }
+
+    #[test]
+    fn test_payload_combines_all_changes() {
+        let doc = document(vec![1], None);
+        let mut update = DocumentUpdate::new(1);
+        update.title = Some("Electricity bill".to_string());
+        update.custom_fields.push(CustomFieldChange { field: Some(8), name: "sender".to_string(), data_type: "string".to_string(), value: json!("ACME") });
+        update.tags = Some(vec![existing(1), existing(3)]);
+        update.document_type = Some(existing(4));
+
+        let payload = update.to_payload(&doc, &Marker::CustomField { field_id: 9 });
+        assert_eq!(payload["title"], json!("Electricity bill"));
+        assert_eq!(payload["custom_fields"], json!([
+            { "field": 7, "value": "kept" },
+            { "field": 8, "value": "ACME" },
+            { "field": 9, "value": true },
+        ]));
+        assert_eq!(payload["tags"], json!([1, 3]));
+        assert_eq!(payload["document_type"], json!(4));
+        assert!(payload.get("correspondent").is_none());
+
+        let previous = previous_values(&doc, &payload);
+        assert_eq!(previous["tags"], json!([1]));
+        assert_eq!(previous["document_type"], Value::Null);
+        assert_eq!(previous.len(), payload.len());
+
+        let payload = DocumentUpdate::new(1).to_payload(&doc, &Marker::Tag { tag_id: 5 });
+        assert_eq!(payload.get("tags"), Some(&json!([1, 5])));
+        assert!(payload.get("custom_fields").is_none());
+
+        let store = Marker::Local(LocalStore::open(PathBuf::from("/nonexistent/processed.json")).unwrap());
+        assert!(DocumentUpdate::new(1).to_payload(&doc, &store).is_empty());
+    }
+}
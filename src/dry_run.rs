@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use reqwest::Client;
+use serde_json::Value;
+use crate::{Document, Field, SelectOption};
+use crate::document_update::{DocumentUpdate, ObjectRef};
+use crate::paperless::{get_default_fields, PaperlessDefaultFieldType};
+
+/// Names of the existing paperless objects, so the report can show names
+/// instead of ids.
+#[derive(Default)]
+pub struct ObjectNames {
+    tags: HashMap<u32, String>,
+    document_types: HashMap<u32, String>,
+    correspondents: HashMap<u32, String>,
+}
+
+impl ObjectNames {
+    pub async fn load(client: &Client, base_url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut names = ObjectNames::default();
+        for field_type in [PaperlessDefaultFieldType::Tag, PaperlessDefaultFieldType::DocumentType, PaperlessDefaultFieldType::Correspondent] {
+            let objects = get_default_fields(client, base_url, field_type).await?;
+            let map = objects.into_iter().filter_map(|o| o.id.map(|id| (id, o.name))).collect();
+            match field_type {
+                PaperlessDefaultFieldType::Tag => names.tags = map,
+                PaperlessDefaultFieldType::DocumentType => names.document_types = map,
+                PaperlessDefaultFieldType::Correspondent => names.correspondents = map,
+            }
+        }
+        Ok(names)
+    }
+
+    pub fn name(&self, field_type: PaperlessDefaultFieldType, id: u32) -> String {
+        self.objects(field_type).get(&id).cloned().unwrap_or_else(|| format!("#{}", id))
+    }
+
+    /// All names of the given type, sorted.
+    pub fn names(&self, field_type: PaperlessDefaultFieldType) -> Vec<String> {
+        let mut names: Vec<String> = self.objects(field_type).values().cloned().collect();
+        names.sort();
+        names
+    }
+
+    fn objects(&self, field_type: PaperlessDefaultFieldType) -> &HashMap<u32, String> {
+        match field_type {
+            PaperlessDefaultFieldType::Tag => &self.tags,
+            PaperlessDefaultFieldType::DocumentType => &self.document_types,
+            PaperlessDefaultFieldType::Correspondent => &self.correspondents,
+        }
+    }
+}
+
+/// Describes what applying `update` would change on the document, old value
+/// first. Objects that do not exist yet are marked with `(new)`.
+pub fn report(update: &DocumentUpdate, document: &Document, fields: &[Field], names: &ObjectNames) -> String {
+    let mut lines = vec![format!("Document {} \"{}\"", document.id, document.title)];
+
+    if let Some(title) = update.title.as_ref().filter(|title| **title != document.title) {
+        lines.push(format!("  title: \"{}\" -> \"{}\"", document.title, title));
+    }
+    for change in &update.custom_fields {
+        let old = change
+            .field
+            .and_then(|id| document.custom_fields.iter().find(|f| f.field == id))
+            .and_then(|f| f.value.clone())
+            .unwrap_or(Value::Null);
+        if change.field.is_some() && old == change.value {
+            continue;
+        }
+        let new_field = match change.field {
+            Some(_) => String::new(),
+            None => format!(" (new {} field)", change.data_type),
+        };
+        lines.push(format!("  custom field {}{}: {} -> {}", change.name, new_field, old, display_value(fields, change.field, &change.value)));
+    }
+    if let Some(tags) = &update.tags {
+        let old: Vec<String> = document.tags.iter().map(|id| names.name(PaperlessDefaultFieldType::Tag, *id)).collect();
+        let new: Vec<String> = tags.iter().map(|tag| object_name(names, PaperlessDefaultFieldType::Tag, tag)).collect();
+        lines.push(format!("  tags: [{}] -> [{}]", old.join(", "), new.join(", ")));
+    }
+    let single_value_fields = [
+        ("document type", PaperlessDefaultFieldType::DocumentType, document.document_type, &update.document_type),
+        ("correspondent", PaperlessDefaultFieldType::Correspondent, document.correspondent, &update.correspondent),
+    ];
+    for (label, field_type, old, new) in single_value_fields {
+        if let Some(new) = new {
+            let old = old.map_or_else(|| "none".to_string(), |id| names.name(field_type, id));
+            lines.push(format!("  {}: {} -> {}", label, old, object_name(names, field_type, new)));
+        }
+    }
+
+    if lines.len() == 1 {
+        lines.push("  no changes".to_string());
+    }
+    lines.join("\n")
+}
+
+fn object_name(names: &ObjectNames, field_type: PaperlessDefaultFieldType, object: &ObjectRef) -> String {
+    match object.id {
+        Some(id) => names.name(field_type, id),
+        None => format!("{} (new)", object.name),
+    }
+}
+
+/// Select values are stored as option ids, the report shows their label.
+fn display_value(fields: &[Field], field_id: Option<u32>, value: &Value) -> String {
+    let label = fields
+        .iter()
+        .find(|f| Some(f.id) == field_id && f.data_type == "select")
+        .and_then(|f| f.extra_data.as_ref())
+        .and_then(|extra| {
+            extra.select_options.iter().enumerate().find(|(index, option)| match option {
+                SelectOption::Option { id, .. } => Some(id.as_str()) == value.as_str(),
+                SelectOption::Label(_) => value.as_u64() == Some(*index as u64),
+            })
+        })
+        .map(|(_, option)| option.label().to_string());
+    label.map_or_else(|| value.to_string(), |label| format!("\"{}\"", label))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::document_update::CustomFieldChange;
+    use crate::CustomField;
+    use crate::test_support::test_document;
+
+    #[test]
+    fn test_report() {
+        let document = Document {
+            title: "scan_0001".to_string(),
+            document_type: Some(2),
+            tags: vec![1],
+            custom_fields: vec![CustomField { field: 7, value: Some(json!("ACME")) }],
+            ..test_document(3)
+        };
+        let names = ObjectNames {
+            tags: HashMap::from([(1, "inbox".to_string())]),
+            document_types: HashMap::from([(2, "Letter".to_string()), (4, "Invoice".to_string())]),
+            correspondents: HashMap::new(),
+        };
+        let mut update = DocumentUpdate::new(3);
+        update.title = Some("Electricity bill".to_string());
+        update.custom_fields = vec![
+            CustomFieldChange { field: Some(7), name: "sender".to_string(), data_type: "string".to_string(), value: json!("ACME") },
+            CustomFieldChange { field: None, name: "amount".to_string(), data_type: "monetary".to_string(), value: json!("EUR12.50") },
+        ];
+        update.tags = Some(vec![ObjectRef { id: Some(1), name: "inbox".to_string() }, ObjectRef { id: None, name: "energy".to_string() }]);
+        update.document_type = Some(ObjectRef { id: Some(4), name: "Invoice".to_string() });
+
+        assert_eq!(report(&update, &document, &[], &names), [
+            "Document 3 \"scan_0001\"",
+            "  title: \"scan_0001\" -> \"Electricity bill\"",
+            "  custom field amount (new monetary field): null -> \"EUR12.50\"",
+            "  tags: [inbox] -> [inbox, energy (new)]",
+            "  document type: Letter -> Invoice",
+        ].join("\n"));
+        assert_eq!(report(&DocumentUpdate::new(3), &document, &[], &names), "Document 3 \"scan_0001\"\n  no changes");
+    }
+}
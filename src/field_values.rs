@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use chrono::{NaiveDate, NaiveDateTime};
+use reqwest::Url;
+use serde_json::{json, Value};
+use crate::{Field, SelectOption};
+use crate::util::normalize_string;
+
+/// Paperless rejects string custom field values longer than this.
+const MAX_STRING_LENGTH: usize = 128;
+
+const DATE_FORMATS: [&str; 10] = [
+    "%Y-%m-%d", "%d.%m.%Y", "%d.%m.%y", "%d/%m/%Y", "%m/%d/%Y", "%Y/%m/%d", "%d-%m-%Y", "%d %B %Y", "%B %d, %Y", "%d. %B %Y",
+];
+
+const GERMAN_MONTHS: [(&str, &str); 12] = [
+    ("januar", "January"), ("februar", "February"), ("märz", "March"), ("april", "April"),
+    ("mai", "May"), ("juni", "June"), ("juli", "July"), ("august", "August"),
+    ("september", "September"), ("oktober", "October"), ("november", "November"), ("dezember", "December"),
+];
+
+/// Active ISO 4217 currency codes.
+const CURRENCY_CODES: &[&str] = &[
+    "AED", "AFN", "ALL", "AMD", "ANG", "AOA", "ARS", "AUD", "AWG", "AZN", "BAM", "BBD", "BDT", "BGN", "BHD", "BIF",
+    "BMD", "BND", "BOB", "BRL", "BSD", "BTN", "BWP", "BYN", "BZD", "CAD", "CDF", "CHF", "CLP", "CNY", "COP", "CRC",
+    "CUP", "CVE", "CZK", "DJF", "DKK", "DOP", "DZD", "EGP", "ERN", "ETB", "EUR", "FJD", "FKP", "GBP", "GEL", "GHS",
+    "GIP", "GMD", "GNF", "GTQ", "GYD", "HKD", "HNL", "HTG", "HUF", "IDR", "ILS", "INR", "IQD", "IRR", "ISK", "JMD",
+    "JOD", "JPY", "KES", "KGS", "KHR", "KMF", "KPW", "KRW", "KWD", "KYD", "KZT", "LAK", "LBP", "LKR", "LRD", "LSL",
+    "LYD", "MAD", "MDL", "MGA", "MKD", "MMK", "MNT", "MOP", "MRU", "MUR", "MVR", "MWK", "MXN", "MYR", "MZN", "NAD",
+    "NGN", "NIO", "NOK", "NPR", "NZD", "OMR", "PAB", "PEN", "PGK", "PHP", "PKR", "PLN", "PYG", "QAR", "RON", "RSD",
+    "RUB", "RWF", "SAR", "SBD", "SCR", "SDG", "SEK", "SGD", "SHP", "SLE", "SOS", "SRD", "SSP", "STN", "SVC", "SYP",
+    "SZL", "THB", "TJS", "TMT", "TND", "TOP", "TRY", "TTD", "TWD", "TZS", "UAH", "UGX", "USD", "UYU", "UZS", "VES",
+    "VND", "VUV", "WST", "XAF", "XCD", "XCG", "XOF", "XPF", "YER", "ZAR", "ZMW", "ZWG",
+];
+
+/// Converts a value produced by the LLM into the representation paperless
+/// expects for the data type of `field`.
+///
+/// Returns the reason as error if the value cannot be converted, so the
+/// field can be skipped instead of failing the whole document update.
+pub fn coerce_value(field: &Field, value: &Value) -> Result<Value, String> {
+    if value.is_null() {
+        return Ok(Value::Null);
+    }
+    match field.data_type.as_str() {
+        "string" => coerce_string(value),
+        "url" => coerce_url(value),
+        "date" => coerce_date(value),
+        "boolean" => coerce_boolean(value),
+        "integer" => coerce_integer(value),
+        "float" => coerce_float(value),
+        "monetary" => coerce_monetary(field, value),
+        "select" => coerce_select(field, value),
+        "documentlink" => coerce_document_link(value),
+        _ => Ok(value.clone()),
+    }
+}
+
+fn as_text(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.trim().to_string()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        Value::Array(values) => Ok(values.iter().map(as_text).collect::<Result<Vec<String>, String>>()?.join(", ")),
+        _ => Err(format!("cannot convert {} to text", value)),
+    }
+}
+
+fn coerce_string(value: &Value) -> Result<Value, String> {
+    let text = as_text(value)?;
+    if text.chars().count() > MAX_STRING_LENGTH {
+        slog_scope::debug!("Truncating value to {} characters: {}", MAX_STRING_LENGTH, text);
+        return Ok(json!(text.chars().take(MAX_STRING_LENGTH).collect::<String>()));
+    }
+    Ok(json!(text))
+}
+
+fn coerce_url(value: &Value) -> Result<Value, String> {
+    let text = as_text(value)?;
+    let candidate = if text.contains("://") { text.clone() } else { format!("https://{}", text) };
+    match Url::parse(&candidate) {
+        Ok(url) if url.host_str().is_some_and(|host| host.contains('.')) => Ok(json!(url.to_string())),
+        _ => Err(format!("'{}' is not a valid url", text)),
+    }
+}
+
+pub fn parse_date(text: &str) -> Option<NaiveDate> {
+    let text = text.trim();
+    parse_english_date(text).or_else(|| parse_english_date(&translate_german_months(text)))
+}
+
+fn parse_english_date(text: &str) -> Option<NaiveDate> {
+    DATE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(text, format).ok())
+        .or_else(|| NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S").ok().map(|dt| dt.date()))
+        .or_else(|| chrono::DateTime::parse_from_rfc3339(text).ok().map(|dt| dt.date_naive()))
+}
+
+/// Replaces German month names by the English ones, whole words only.
+fn translate_german_months(text: &str) -> String {
+    let mut translated = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(char::is_alphabetic) {
+        translated.push_str(&rest[..start]);
+        let end = rest[start..].find(|c: char| !c.is_alphabetic()).map_or(rest.len(), |i| start + i);
+        let word = &rest[start..end];
+        let month = GERMAN_MONTHS.iter().find(|(german, _)| word.to_lowercase() == *german);
+        translated.push_str(month.map_or(word, |(_, english)| english));
+        rest = &rest[end..];
+    }
+    translated.push_str(rest);
+    translated
+}
+
+fn coerce_date(value: &Value) -> Result<Value, String> {
+    let text = as_text(value)?;
+    match parse_date(&text) {
+        Some(date) => Ok(json!(date.format("%Y-%m-%d").to_string())),
+        None => Err(format!("'{}' is not a recognized date", text)),
+    }
+}
+
+pub fn parse_boolean(text: &str) -> Option<bool> {
+    match text.trim().to_lowercase().as_str() {
+        "true" | "yes" | "y" | "1" | "ja" | "oui" | "wahr" => Some(true),
+        "false" | "no" | "n" | "0" | "nein" | "non" | "falsch" => Some(false),
+        _ => None,
+    }
+}
+
+fn coerce_boolean(value: &Value) -> Result<Value, String> {
+    match value {
+        Value::Bool(b) => Ok(json!(b)),
+        _ => {
+            let text = as_text(value)?;
+            parse_boolean(&text).map(|b| json!(b)).ok_or(format!("'{}' is not a boolean", text))
+        }
+    }
+}
+
+/// Parses numbers written with either `.` or `,` as decimal separator,
+/// e.g. "1.234,56", "1,234.56" or "12,5".
+pub fn parse_number(text: &str) -> Option<f64> {
+    let cleaned: String = text
+        .chars()
+        .filter(|c| c.is_ascii_digit() || matches!(c, '.' | ',' | '-'))
+        .collect();
+    if !cleaned.chars().any(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let decimal_separator = match (cleaned.rfind('.'), cleaned.rfind(',')) {
+        (Some(dot), Some(comma)) => Some(if dot > comma { '.' } else { ',' }),
+        (None, Some(comma)) if cleaned.matches(',').count() == 1 && cleaned.len() - comma - 1 != 3 => Some(','),
+        (Some(dot), None) if cleaned.matches('.').count() == 1 && (cleaned.len() - dot - 1 != 3 || cleaned.starts_with("0")) => Some('.'),
+        _ => None,
+    };
+    let normalized: String = cleaned
+        .chars()
+        .filter_map(|c| match c {
+            '.' | ',' if Some(c) == decimal_separator => Some('.'),
+            '.' | ',' => None,
+            _ => Some(c),
+        })
+        .collect();
+    normalized.parse::<f64>().ok()
+}
+
+fn coerce_integer(value: &Value) -> Result<Value, String> {
+    let number = match value {
+        Value::Number(n) => n.as_f64(),
+        _ => parse_number(&as_text(value)?),
+    };
+    match number {
+        Some(n) if n.fract() == 0.0 => Ok(json!(n as i64)),
+        _ => Err(format!("{} is not an integer", value)),
+    }
+}
+
+fn coerce_float(value: &Value) -> Result<Value, String> {
+    let number = match value {
+        Value::Number(n) => n.as_f64(),
+        _ => parse_number(&as_text(value)?),
+    };
+    number.map(|n| json!(n)).ok_or(format!("{} is not a number", value))
+}
+
+/// Finds the currency of an amount by its symbol or an uppercase ISO 4217
+/// code written next to the number. Other uppercase codes next to the number
+/// are rejected instead of being taken for a currency.
+fn currency_code(text: &str) -> Result<Option<String>, String> {
+    if text.contains('€') {
+        return Ok(Some("EUR".to_string()));
+    }
+    if text.contains('$') {
+        return Ok(Some("USD".to_string()));
+    }
+    if text.contains('£') {
+        return Ok(Some("GBP".to_string()));
+    }
+    let next_to_number = |start: usize, end: usize| {
+        text[..start].trim_end().ends_with(|c: char| c.is_ascii_digit())
+            || text[end..].trim_start().starts_with(|c: char| c.is_ascii_digit() || c == '-')
+    };
+    let words = text
+        .char_indices()
+        .filter(|&(i, c)| c.is_alphabetic() && !text[..i].ends_with(char::is_alphabetic))
+        .map(|(i, _)| (i, text[i..].find(|c: char| !c.is_alphabetic()).map_or(text.len(), |end| i + end)));
+    for (start, end) in words {
+        let word = &text[start..end];
+        if word.len() != 3 || !word.chars().all(|c| c.is_ascii_uppercase()) || !next_to_number(start, end) {
+            continue;
+        }
+        if !CURRENCY_CODES.contains(&word) {
+            return Err(format!("{} is not an ISO 4217 currency code", word));
+        }
+        return Ok(Some(word.to_string()));
+    }
+    Ok(None)
+}
+
+fn coerce_monetary(field: &Field, value: &Value) -> Result<Value, String> {
+    let text = as_text(value)?;
+    let amount = parse_number(&text).ok_or(format!("'{}' is not a monetary amount", text))?;
+    let currency = currency_code(&text)?
+        .or_else(|| field.extra_data.as_ref().and_then(|extra| extra.default_currency.clone()))
+        .unwrap_or_default();
+    Ok(json!(format!("{}{:.2}", currency, amount)))
+}
+
+fn coerce_select(field: &Field, value: &Value) -> Result<Value, String> {
+    let text = as_text(value)?;
+    let options = field.extra_data.as_ref().map(|extra| extra.select_options.as_slice()).unwrap_or_default();
+    options
+        .iter()
+        .enumerate()
+        .find(|(_, option)| normalize_string(option.label()) == normalize_string(&text))
+        .map(|(index, option)| match option {
+            SelectOption::Label(_) => json!(index),
+            SelectOption::Option { id, .. } => json!(id),
+        })
+        .ok_or(format!("'{}' is not one of the select options", text))
+}
+
+fn coerce_document_link(value: &Value) -> Result<Value, String> {
+    let values = match value {
+        Value::Array(values) => values.clone(),
+        _ => vec![value.clone()],
+    };
+    values
+        .iter()
+        .map(|v| match v {
+            Value::Number(n) if n.is_u64() => Ok(json!(n)),
+            _ => as_text(v)?.parse::<u64>().map(|id| json!(id)).map_err(|_| format!("{} is not a document id", v)),
+        })
+        .collect::<Result<Vec<Value>, String>>()
+        .map(Value::Array)
+}
+
+/// Data types accepted by paperless for custom fields.
+pub const DATA_TYPES: [&str; 9] = ["string", "url", "date", "boolean", "integer", "float", "monetary", "documentlink", "select"];
+
+const DATE_KEY_HINTS: [&str; 4] = ["date", "datum", "day", "deadline"];
+const AMOUNT_KEY_HINTS: [&str; 8] = ["amount", "price", "total", "cost", "fee", "betrag", "preis", "summe"];
+
+/// Infers the paperless data type for a new custom field from the first value
+/// the LLM produced for it and the field name. `overrides` maps field names to
+/// a configured data type and takes precedence.
+pub fn infer_data_type(key: &str, value: &Value, overrides: &HashMap<String, String>) -> String {
+    if let Some(data_type) = overrides.get(key) {
+        return data_type.clone();
+    }
+    let key = key.to_lowercase();
+    let has_hint = |hints: &[&str]| hints.iter().any(|hint| key.contains(hint));
+    let data_type = match value {
+        Value::Bool(_) => "boolean",
+        Value::Number(_) if has_hint(&AMOUNT_KEY_HINTS) => "monetary",
+        Value::Number(n) if n.is_f64() => "float",
+        Value::Number(_) => "integer",
+        Value::String(text) => {
+            let text = text.trim();
+            if text.eq_ignore_ascii_case("true") || text.eq_ignore_ascii_case("false") {
+                "boolean"
+            } else if text.starts_with("http://") || text.starts_with("https://") || text.starts_with("www.") {
+                "url"
+            } else if parse_date(text).is_some() && (has_hint(&DATE_KEY_HINTS) || text.len() >= 8) {
+                "date"
+            } else if parse_number(text).is_some() && (has_hint(&AMOUNT_KEY_HINTS) || looks_monetary(text)) {
+                "monetary"
+            } else {
+                "string"
+            }
+        }
+        _ => "string",
+    };
+    data_type.to_string()
+}
+
+fn looks_monetary(text: &str) -> bool {
+    let rest: String = text.chars().filter(|c| !c.is_ascii_digit() && !matches!(c, '.' | ',' | ' ' | '-')).collect();
+    ["€", "$", "£"].contains(&rest.as_str()) || (rest.len() == 3 && rest.chars().all(|c| c.is_ascii_uppercase()))
+}
+
+/// Parses a `name=type` list, e.g. "date_received=date,total=monetary".
+pub fn parse_field_type_overrides(value: &str) -> Result<HashMap<String, String>, String> {
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (name, data_type) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=type, got '{}'", entry.trim()))?;
+            let data_type = data_type.trim().to_lowercase();
+            if !DATA_TYPES.contains(&data_type.as_str()) {
+                return Err(format!("unknown data type '{}' for field {}, expected one of {}", data_type, name.trim(), DATA_TYPES.join(", ")));
+            }
+            Ok((name.trim().to_string(), data_type))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FieldExtraData;
+
+    fn field(data_type: &str, extra_data: Option<FieldExtraData>) -> Field {
+        Field {
+            id: 1,
+            name: "field".to_string(),
+            data_type: data_type.to_string(),
+            extra_data,
+        }
+    }
+
+    #[test]
+    fn test_coerce_dates() {
+        let date = field("date", None);
+        assert_eq!(coerce_value(&date, &json!("12.03.2024")).unwrap(), json!("2024-03-12"));
+        assert_eq!(coerce_value(&date, &json!("2024-03-12T10:00:00")).unwrap(), json!("2024-03-12"));
+        assert_eq!(coerce_value(&date, &json!("12. März 2024")).unwrap(), json!("2024-03-12"));
+        assert_eq!(coerce_value(&date, &json!("March 12, 2024")).unwrap(), json!("2024-03-12"));
+        assert_eq!(coerce_value(&date, &json!("January 12, 2024")).unwrap(), json!("2024-01-12"));
+        assert_eq!(coerce_value(&date, &json!("12 February 2024")).unwrap(), json!("2024-02-12"));
+        assert_eq!(coerce_value(&date, &json!("1. Mai 2024")).unwrap(), json!("2024-05-01"));
+        assert_eq!(coerce_value(&date, &json!("3. Dezember 2024")).unwrap(), json!("2024-12-03"));
+        assert!(coerce_value(&date, &json!("İİ 12.03.2024")).is_err());
+        assert!(coerce_value(&date, &json!("soon")).is_err());
+    }
+
+    #[test]
+    fn test_coerce_numbers() {
+        assert_eq!(coerce_value(&field("monetary", None), &json!("EUR 12,50")).unwrap(), json!("EUR12.50"));
+        assert_eq!(coerce_value(&field("monetary", None), &json!("1.234,5 €")).unwrap(), json!("EUR1234.50"));
+        let default_currency = FieldExtraData {
+            select_options: Vec::new(),
+            default_currency: Some("CHF".to_string()),
+        };
+        assert_eq!(coerce_value(&field("monetary", Some(default_currency.clone())), &json!(7)).unwrap(), json!("CHF7.00"));
+        assert_eq!(coerce_value(&field("monetary", Some(default_currency.clone())), &json!("12.50 net")).unwrap(), json!("CHF12.50"));
+        assert_eq!(coerce_value(&field("monetary", Some(default_currency)), &json!("Fee 12")).unwrap(), json!("CHF12.00"));
+        assert_eq!(coerce_value(&field("monetary", None), &json!("12.50 net")).unwrap(), json!("12.50"));
+        assert_eq!(coerce_value(&field("monetary", None), &json!("12 SEK")).unwrap(), json!("SEK12.00"));
+        assert_eq!(coerce_value(&field("monetary", None), &json!("try 12 rub")).unwrap(), json!("12.00"));
+        assert_eq!(coerce_value(&field("monetary", None), &json!("CAD in total: 12")).unwrap(), json!("12.00"));
+        assert!(coerce_value(&field("monetary", None), &json!("12.50 XBT")).is_err());
+        assert_eq!(coerce_value(&field("integer", None), &json!("1,000")).unwrap(), json!(1000));
+        assert!(coerce_value(&field("integer", None), &json!("12.5")).is_err());
+        assert_eq!(coerce_value(&field("float", None), &json!("12,5")).unwrap(), json!(12.5));
+    }
+
+    #[test]
+    fn test_coerce_select_and_boolean() {
+        let options = FieldExtraData {
+            select_options: vec![
+                SelectOption::Option { id: "a1".to_string(), label: "Low".to_string() },
+                SelectOption::Option { id: "b2".to_string(), label: "High".to_string() },
+            ],
+            default_currency: None,
+        };
+        assert_eq!(coerce_value(&field("select", Some(options)), &json!("high")).unwrap(), json!("b2"));
+        let legacy = FieldExtraData {
+            select_options: vec![SelectOption::Label("Low".to_string()), SelectOption::Label("High".to_string())],
+            default_currency: None,
+        };
+        assert_eq!(coerce_value(&field("select", Some(legacy)), &json!("High")).unwrap(), json!(1));
+        assert!(coerce_value(&field("select", None), &json!("High")).is_err());
+        assert_eq!(coerce_value(&field("boolean", None), &json!("Ja")).unwrap(), json!(true));
+        assert_eq!(coerce_value(&field("url", None), &json!("www.example.com")).unwrap(), json!("https://www.example.com/"));
+    }
+
+    #[test]
+    fn test_infer_data_type() {
+        assert!(parse_field_type_overrides("invoice_number=integer, topic=unknown").is_err());
+        let overrides = parse_field_type_overrides("invoice_number=integer,").unwrap();
+        assert_eq!(overrides.len(), 1);
+        assert_eq!(infer_data_type("invoice_number", &json!("2024-0012"), &overrides), "integer");
+        assert_eq!(infer_data_type("date_received", &json!("12.03.2024"), &overrides), "date");
+        assert_eq!(infer_data_type("total", &json!("12,50"), &overrides), "monetary");
+        assert_eq!(infer_data_type("fee", &json!("EUR 3.10"), &overrides), "monetary");
+        assert_eq!(infer_data_type("pages", &json!(3), &overrides), "integer");
+        assert_eq!(infer_data_type("paid", &json!(true), &overrides), "boolean");
+        assert_eq!(infer_data_type("website", &json!("https://example.com"), &overrides), "url");
+        assert_eq!(infer_data_type("topic", &json!("Tax 2024"), &overrides), "string");
+    }
+}
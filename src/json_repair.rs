@@ -0,0 +1,348 @@
+use std::fmt;
+use serde::de::DeserializeOwned;
+
+/// A fix applied to malformed LLM output before it could be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Repair {
+    CodeFence,
+    Comments,
+    TrailingCommas,
+    SingleQuotes,
+    UnquotedKeys,
+    PythonLiterals,
+    Truncated,
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Repair::CodeFence => "markdown code fence",
+            Repair::Comments => "comments",
+            Repair::TrailingCommas => "trailing commas",
+            Repair::SingleQuotes => "single quotes",
+            Repair::UnquotedKeys => "unquoted keys",
+            Repair::PythonLiterals => "python literals",
+            Repair::Truncated => "truncated output",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Parses the first JSON object or array in an LLM answer.
+///
+/// The answer is parsed as is first, if that fails the common model mistakes
+/// are repaired. The applied repairs are returned alongside the value.
+pub fn parse_json<T: DeserializeOwned>(input: &str) -> Result<(T, Vec<Repair>), String> {
+    if let Ok(json_str) = extract_json_object(input) {
+        if let Ok(value) = serde_json::from_str(&json_str) {
+            return Ok((value, Vec::new()));
+        }
+    }
+    let (json_str, repairs) = repair_json(input)?;
+    slog_scope::debug!("Repaired JSON: {}", json_str);
+    match serde_json::from_str(&json_str) {
+        Ok(value) => Ok((value, repairs)),
+        Err(e) => Err(format!("Error parsing llm response json {}, JSON String was: {}", e, json_str)),
+    }
+}
+
+/// Returns the first balanced JSON object or array in `input`.
+/// Brackets inside string literals are ignored.
+pub fn extract_json_object(input: &str) -> Result<String, String> {
+    let mut brace_count = 0;
+    let mut json_start = None;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in input.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' if json_start.is_some() => in_string = true,
+            '{' | '[' => {
+                if brace_count == 0 {
+                    json_start = Some(i);
+                }
+                brace_count += 1;
+            }
+            '}' | ']' if brace_count > 0 => {
+                brace_count -= 1;
+                if brace_count == 0 {
+                    let json = &input[json_start.unwrap_or(0)..=i];
+                    slog_scope::debug!("{}", json);
+                    return Ok(json.to_string()); // Found the complete JSON object
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let error_msg = "No JSON object found in the response!".to_string();
+    slog_scope::debug!("{}", error_msg);
+    Err(error_msg)
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open(char),
+    Close(char),
+    Colon,
+    Comma,
+    Str(String),
+    Word(String),
+}
+
+/// Rewrites a malformed JSON answer into valid JSON.
+pub fn repair_json(input: &str) -> Result<(String, Vec<Repair>), String> {
+    let mut repairs = Vec::new();
+    let input = strip_code_fence(input, &mut repairs);
+    let start = input.find(['{', '[']).ok_or("No JSON object found in the response!")?;
+    let tokens = tokenize(&input[start..], &mut repairs);
+    Ok((write_tokens(tokens, &mut repairs), repairs))
+}
+
+fn note(repairs: &mut Vec<Repair>, repair: Repair) {
+    if !repairs.contains(&repair) {
+        repairs.push(repair);
+    }
+}
+
+fn strip_code_fence<'a>(input: &'a str, repairs: &mut Vec<Repair>) -> &'a str {
+    let Some(fence) = input.find("```") else {
+        return input;
+    };
+    note(repairs, Repair::CodeFence);
+    // Skip the language tag, e.g. ```json
+    let body = &input[fence + 3..];
+    let body = body.find('\n').map_or(body, |newline| &body[newline + 1..]);
+    match body.find("```") {
+        Some(end) => &body[..end],
+        None => body,
+    }
+}
+
+fn tokenize(input: &str, repairs: &mut Vec<Repair>) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' | '[' => tokens.push(Token::Open(c)),
+            '}' | ']' => tokens.push(Token::Close(c)),
+            ':' => tokens.push(Token::Colon),
+            ',' => tokens.push(Token::Comma),
+            '"' | '\'' => {
+                if c == '\'' {
+                    note(repairs, Repair::SingleQuotes);
+                }
+                let mut value = String::new();
+                let mut closed = false;
+                while let Some(next) = chars.next() {
+                    match next {
+                        '\\' => match chars.next() {
+                            Some('n') => value.push('\n'),
+                            Some('t') => value.push('\t'),
+                            Some('r') => value.push('\r'),
+                            Some(escaped) => value.push(escaped),
+                            None => break,
+                        },
+                        _ if next == c => {
+                            closed = true;
+                            break;
+                        }
+                        _ => value.push(next),
+                    }
+                }
+                // An unterminated string can only be the end of truncated output
+                if closed {
+                    tokens.push(Token::Str(value));
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                note(repairs, Repair::Comments);
+                for next in chars.by_ref() {
+                    if next == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                note(repairs, Repair::Comments);
+                chars.next();
+                let mut previous = ' ';
+                for next in chars.by_ref() {
+                    if previous == '*' && next == '/' {
+                        break;
+                    }
+                    previous = next;
+                }
+            }
+            _ if c.is_whitespace() => {}
+            _ => {
+                let mut word = c.to_string();
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || "{}[]:,\"'".contains(next) {
+                        break;
+                    }
+                    word.push(next);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    tokens
+}
+
+fn write_tokens(tokens: Vec<Token>, repairs: &mut Vec<Repair>) -> String {
+    let mut out = String::new();
+    // Open containers, and whether an object currently expects a key
+    let mut stack: Vec<char> = Vec::new();
+    let mut expect_key = false;
+    // Length of `out` at the end of the last complete value
+    let mut safe = 0;
+
+    for token in tokens {
+        match token {
+            Token::Open(c) => {
+                out.push(c);
+                stack.push(c);
+                expect_key = c == '{';
+                safe = out.len();
+            }
+            Token::Close(c) => {
+                let open = if c == '}' { '{' } else { '[' };
+                if stack.last() != Some(&open) {
+                    continue;
+                }
+                if out.ends_with(',') {
+                    note(repairs, Repair::TrailingCommas);
+                    out.pop();
+                }
+                out.push(c);
+                stack.pop();
+                expect_key = false;
+                safe = out.len();
+                if stack.is_empty() {
+                    return out;
+                }
+            }
+            Token::Colon => {
+                out.push(':');
+                expect_key = false;
+            }
+            Token::Comma => {
+                if !out.ends_with(',') && !out.ends_with(['{', '[']) {
+                    out.push(',');
+                }
+                expect_key = stack.last() == Some(&'{');
+            }
+            Token::Str(value) => {
+                out.push_str(&serde_json::Value::String(value).to_string());
+                if !expect_key {
+                    safe = out.len();
+                }
+            }
+            Token::Word(word) => {
+                if expect_key {
+                    note(repairs, Repair::UnquotedKeys);
+                    out.push_str(&serde_json::Value::String(word).to_string());
+                    continue;
+                }
+                let literal = match word.as_str() {
+                    "None" => Some("null"),
+                    "True" => Some("true"),
+                    "False" => Some("false"),
+                    _ => None,
+                };
+                match literal {
+                    Some(literal) => {
+                        note(repairs, Repair::PythonLiterals);
+                        out.push_str(literal);
+                    }
+                    None => out.push_str(&word),
+                }
+                safe = out.len();
+            }
+        }
+    }
+
+    // Input ended before every container was closed
+    note(repairs, Repair::Truncated);
+    out.truncate(safe);
+    if out.ends_with(',') {
+        out.pop();
+    }
+    while let Some(open) = stack.pop() {
+        out.push(if open == '{' { '}' } else { ']' });
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn repaired(input: &str) -> (Value, Vec<Repair>) {
+        parse_json(input).unwrap()
+    }
+
+    #[test]
+    fn test_extract_json_object() {
+        let json_str = "Some text before JSON object {\"key\": \"value\"} Some text after";
+        assert_eq!(
+            extract_json_object(json_str).unwrap(),
+            "{\"key\": \"value\"}"
+        );
+
+        let json_array_str = "Some text before JSON array [1,2,3] Some text after";
+        assert_eq!(
+            extract_json_object(json_array_str).unwrap(),
+            "[1,2,3]"
+        );
+
+        let braces_in_string = "{\"title\": \"Invoice {draft}\", \"note\": \"a } b\"} trailing";
+        assert_eq!(
+            extract_json_object(braces_in_string).unwrap(),
+            "{\"title\": \"Invoice {draft}\", \"note\": \"a } b\"}"
+        );
+
+        let empty_json_str = "No JSON object or array here";
+        assert!(extract_json_object(empty_json_str).is_err());
+    }
+
+    #[test]
+    fn test_valid_json_needs_no_repair() {
+        let (value, repairs) = repaired("Here it is: {\"title\": \"Invoice {draft}\"}");
+        assert_eq!(value, json!({"title": "Invoice {draft}"}));
+        assert!(repairs.is_empty());
+    }
+
+    #[test]
+    fn test_repair_common_mistakes() {
+        let input = "```json\n{\n  title: 'Bob\\'s \"invoice\"', // the title\n  'paid': True,\n  \"due\": None,\n  \"tags\": [\"a\", \"b\",],\n}\n```";
+        let (value, repairs) = repaired(input);
+        assert_eq!(value, json!({"title": "Bob's \"invoice\"", "paid": true, "due": null, "tags": ["a", "b"]}));
+        for repair in [Repair::CodeFence, Repair::Comments, Repair::UnquotedKeys, Repair::SingleQuotes, Repair::PythonLiterals, Repair::TrailingCommas] {
+            assert!(repairs.contains(&repair), "missing {}", repair);
+        }
+    }
+
+    #[test]
+    fn test_repair_truncated_output() {
+        let (value, repairs) = repaired("{\"title\": \"Invoice\", \"sender\": \"ACME\", \"recipient\": \"Jo");
+        assert_eq!(value, json!({"title": "Invoice", "sender": "ACME"}));
+        assert_eq!(repairs, vec![Repair::Truncated]);
+
+        let (value, _) = repaired("[\"tax\", \"insurance\", \"car");
+        assert_eq!(value, json!(["tax", "insurance"]));
+    }
+}
@@ -0,0 +1,39 @@
+use whatlang::Lang;
+
+/// Languages the built-in prompt is translated to.
+pub const PROMPT_LANGUAGES: [&str; 3] = ["EN", "DE", "FR"];
+/// Detecting the language of the beginning is enough and keeps it fast.
+const SAMPLE_CHARS: usize = 10_000;
+
+/// The language of the text, `None` if it cannot be told reliably.
+pub fn detect_language(text: &str) -> Option<Lang> {
+    let sample = text.char_indices().nth(SAMPLE_CHARS).map_or(text, |(end, _)| &text[..end]);
+    whatlang::detect(sample).filter(|info| info.is_reliable()).map(|info| info.lang())
+}
+
+/// The prompt translation for the language, English if there is none.
+pub fn prompt_language(language: Option<Lang>) -> &'static str {
+    match language {
+        Some(Lang::Deu) => "DE",
+        Some(Lang::Fra) => "FR",
+        _ => "EN",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_language() {
+        let german = detect_language("Sehr geehrte Damen und Herren, anbei erhalten Sie die Rechnung für den Monat März. Bitte überweisen Sie den Betrag bis zum Ende des Monats.");
+        assert_eq!(german, Some(Lang::Deu));
+        assert_eq!(prompt_language(german), "DE");
+        let french = detect_language("Madame, Monsieur, veuillez trouver ci-joint la facture du mois de mars. Merci de régler le montant avant la fin du mois.");
+        assert_eq!(prompt_language(french), "FR");
+        let spanish = detect_language("Estimados señores, adjunto encontrarán la factura del mes de marzo. Por favor, paguen el importe antes de fin de mes.");
+        assert_eq!(spanish, Some(Lang::Spa));
+        assert_eq!(prompt_language(spanish), "EN");
+        assert_eq!(detect_language("12.03.2024"), None);
+    }
+}
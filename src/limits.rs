@@ -0,0 +1,99 @@
+use async_trait::async_trait;
+use serde_json::Value;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use crate::llm_api::{ChatMessage, LlmBackend, LlmError, LlmResponse, ModelInfo};
+
+/// Caps the number of requests in flight to the LLM and to Paperless, each
+/// limit is shared by all documents processed concurrently.
+pub struct Limits {
+    llm: Semaphore,
+    paperless: Semaphore,
+    /// Number of documents processed at the same time
+    pub documents: usize,
+}
+
+impl Limits {
+    pub fn new(llm: usize, paperless: usize) -> Self {
+        Limits { llm: Semaphore::new(llm), paperless: Semaphore::new(paperless), documents: llm }
+    }
+
+    /// Waits until another request to Paperless may be sent.
+    pub async fn paperless(&self) -> SemaphorePermit<'_> {
+        acquire(&self.paperless).await
+    }
+
+    /// Wraps `backend` so every request waits for a free LLM slot.
+    pub fn llm<'a>(&'a self, backend: &'a dyn LlmBackend) -> LimitedLlm<'a> {
+        LimitedLlm { backend, permits: &self.llm }
+    }
+}
+
+async fn acquire(semaphore: &Semaphore) -> SemaphorePermit<'_> {
+    // The semaphores are never closed
+    semaphore.acquire().await.expect("semaphore closed")
+}
+
+pub struct LimitedLlm<'a> {
+    backend: &'a dyn LlmBackend,
+    permits: &'a Semaphore,
+}
+
+#[async_trait]
+impl LlmBackend for LimitedLlm<'_> {
+    fn model_info(&self) -> ModelInfo {
+        self.backend.model_info()
+    }
+
+    async fn generate(&self, prompt: String, schema: Option<&Value>) -> Result<LlmResponse, LlmError> {
+        let _permit = acquire(self.permits).await;
+        self.backend.generate(prompt, schema).await
+    }
+
+    async fn chat(&self, messages: Vec<ChatMessage>, schema: Option<&Value>) -> Result<LlmResponse, LlmError> {
+        let _permit = acquire(self.permits).await;
+        self.backend.chat(messages, schema).await
+    }
+
+    async fn check(&self) -> Result<(), LlmError> {
+        self.backend.check().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Counts how many requests run at the same time.
+    #[derive(Default)]
+    struct Counting {
+        running: AtomicUsize,
+        max: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl LlmBackend for Counting {
+        fn model_info(&self) -> ModelInfo {
+            ModelInfo { backend: "test", name: "test".to_string(), structured_output: false }
+        }
+
+        async fn generate(&self, _prompt: String, _schema: Option<&Value>) -> Result<LlmResponse, LlmError> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(LlmResponse { response: String::new() })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_llm_limit() {
+        let backend = Counting::default();
+        let limits = Limits::new(2, 1);
+        let llm = limits.llm(&backend);
+        let requests = (0..5).map(|_| llm.generate(String::new(), None));
+        futures::future::join_all(requests).await;
+        assert_eq!(backend.max.load(Ordering::SeqCst), 2);
+    }
+}
@@ -1,23 +1,139 @@
-use ollama_rs::generation::completion::GenerationResponse;
-use ollama_rs::generation::completion::request::GenerationRequest;
-use ollama_rs::Ollama;
+use async_trait::async_trait;
+use serde_json::Value;
+
+pub type LlmError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Metadata describing the model a backend talks to.
+#[derive(Debug, Clone)]
+pub struct ModelInfo {
+    pub backend: &'static str,
+    pub name: String,
+    /// Whether the backend constrains its output to the JSON schema passed
+    /// with a request.
+    pub structured_output: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct LlmResponse {
+    pub response: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatRole {
+    System,
+    User,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: ChatRole, content: String) -> Self {
+        ChatMessage { role, content }
+    }
+}
+
+/// A language model server doclytics can send prompts to.
+///
+/// The processing pipeline only talks to this trait, so any inference server
+/// (or a test double) can be plugged in by implementing `generate`.
+/// `schema` is a JSON schema the answer should follow; backends without
+/// structured output support ignore it.
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    fn model_info(&self) -> ModelInfo;
+
+    async fn generate(&self, prompt: String, schema: Option<&Value>) -> Result<LlmResponse, LlmError>;
+
+    /// Backends without a native chat endpoint fall back to a single prompt
+    /// containing all messages, e.g. the base prompt followed by the document.
+    async fn chat(&self, messages: Vec<ChatMessage>, schema: Option<&Value>) -> Result<LlmResponse, LlmError> {
+        let prompt = messages
+            .into_iter()
+            .map(|message| message.content)
+            .collect::<Vec<String>>()
+            .join(" ");
+        self.generate(prompt, schema).await
+    }
+
+    /// Verifies that the server is reachable and serves the model. Backends
+    /// without a cheaper way fall back to a tiny prompt.
+    async fn check(&self) -> Result<(), LlmError> {
+        self.generate("Reply with OK".to_string(), None).await.map(|_| ())
+    }
+}
 
 pub async fn generate_response(
-    ollama: &Ollama,
-    model: &String,
+    llm: &dyn LlmBackend,
     prompt: String,
-) -> std::result::Result<GenerationResponse, Box<dyn std::error::Error>> {
-    let res = ollama
-        .generate(GenerationRequest::new(model.clone(), prompt))
-        .await;
-    match res { 
+    schema: Option<&Value>,
+) -> Result<LlmResponse, LlmError> {
+    let model = llm.model_info();
+    slog_scope::debug!("Sending prompt to {} model {}", model.backend, model.name);
+    let res = llm.generate(prompt, schema).await;
+    match res {
         Ok(res) => {
-           slog_scope::debug!("Response from ollama:\n {}", res.response);
+            slog_scope::debug!("Response from {}:\n {}", model.backend, res.response);
             Ok(res)
-        },
+        }
         Err(e) => {
             slog_scope::error!("{}", e);
-            Err(e.into())
+            Err(e)
         }
     }
-}
\ No newline at end of file
+}
+
+pub async fn chat_response(
+    llm: &dyn LlmBackend,
+    messages: Vec<ChatMessage>,
+    schema: Option<&Value>,
+) -> Result<LlmResponse, LlmError> {
+    let model = llm.model_info();
+    slog_scope::debug!("Sending {} chat messages to {} model {}", messages.len(), model.backend, model.name);
+    let res = llm.chat(messages, schema).await;
+    match res {
+        Ok(res) => {
+            slog_scope::debug!("Response from {}:\n {}", model.backend, res.response);
+            Ok(res)
+        }
+        Err(e) => {
+            slog_scope::error!("{}", e);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct EchoBackend;
+
+    #[async_trait]
+    impl LlmBackend for EchoBackend {
+        fn model_info(&self) -> ModelInfo {
+            ModelInfo {
+                backend: "echo",
+                name: "echo".to_string(),
+                structured_output: false,
+            }
+        }
+
+        async fn generate(&self, prompt: String, _schema: Option<&Value>) -> Result<LlmResponse, LlmError> {
+            Ok(LlmResponse { response: prompt })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_chat_falls_back_to_generate() {
+        let messages = vec![
+            ChatMessage::new(ChatRole::System, "instruction".to_string()),
+            ChatMessage::new(ChatRole::User, "document".to_string()),
+        ];
+        let res = chat_response(&EchoBackend, messages, None).await.unwrap();
+        assert_eq!(res.response, "instruction document");
+    }
+}
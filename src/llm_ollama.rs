@@ -0,0 +1,121 @@
+use async_trait::async_trait;
+use ollama_rs::generation::completion::request::GenerationRequest;
+use ollama_rs::generation::parameters::{FormatType, JsonStructure};
+use ollama_rs::models::ModelOptions;
+use ollama_rs::error::OllamaError;
+use ollama_rs::Ollama;
+use schemars::Schema;
+use serde_json::Value;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::llm_api::{LlmBackend, LlmError, LlmResponse, ModelInfo};
+use crate::error::ResponseError;
+
+pub struct OllamaBackend {
+    ollama: Ollama,
+    model: String,
+    /// Cleared when the server rejects a schema, e.g. Ollama before 0.5
+    structured_output: AtomicBool,
+    /// Sent as `num_ctx`, otherwise Ollama truncates prompts to its default
+    context_size: Option<usize>,
+}
+
+impl OllamaBackend {
+    pub fn new(host: &str, port: u16, secure_endpoint: bool, model: &str, structured_output: bool, context_size: Option<usize>) -> Self {
+        let protocol = if secure_endpoint { "https" } else { "http" };
+        let ollama_base_url = format!("{}://{}", protocol, host);
+        OllamaBackend {
+            ollama: Ollama::builder().host(ollama_base_url).port(port).build(),
+            model: model.to_string(),
+            structured_output: AtomicBool::new(structured_output),
+            context_size,
+        }
+    }
+
+    fn request(&self, prompt: String) -> GenerationRequest<'static> {
+        let request = GenerationRequest::new(self.model.clone(), prompt);
+        match self.context_size {
+            Some(size) => request.options(ModelOptions::default().num_ctx(size as u64)),
+            None => request,
+        }
+    }
+
+    /// Converts the requested JSON schema into an Ollama `format` parameter.
+    /// Returns `None` when structured output is disabled, e.g. for Ollama
+    /// releases before 0.5 which do not accept a schema.
+    fn format(&self, schema: Option<&Value>) -> Result<Option<FormatType>, LlmError> {
+        match schema {
+            Some(schema) if self.structured_output.load(Ordering::Relaxed) => {
+                let schema = Schema::try_from(schema.clone())?;
+                Ok(Some(FormatType::StructuredJson(Box::new(JsonStructure::new_for_schema(schema)))))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Whether Ollama refused the `format` parameter, e.g. releases before 0.5
+/// answer "cannot unmarshal object into Go struct field
+/// GenerateRequest.format of type string". Timeouts and server errors are
+/// not a reason to drop the schema.
+fn rejects_format(error: &OllamaError) -> bool {
+    let message = match error {
+        OllamaError::Other(message) => message,
+        OllamaError::InternalError(error) => &error.message,
+        _ => return false,
+    };
+    message.to_lowercase().contains("format")
+}
+
+#[async_trait]
+impl LlmBackend for OllamaBackend {
+    fn model_info(&self) -> ModelInfo {
+        ModelInfo {
+            backend: "ollama",
+            name: self.model.clone(),
+            structured_output: self.structured_output.load(Ordering::Relaxed),
+        }
+    }
+
+    async fn generate(&self, prompt: String, schema: Option<&Value>) -> Result<LlmResponse, LlmError> {
+        let Some(format) = self.format(schema)? else {
+            let res = self.ollama.generate(self.request(prompt)).await?;
+            return Ok(LlmResponse { response: res.response });
+        };
+        let structured = self.request(prompt.clone()).format(format);
+        let error = match self.ollama.generate(structured).await {
+            Ok(res) => return Ok(LlmResponse { response: res.response }),
+            Err(e) if rejects_format(&e) => e,
+            Err(e) => return Err(e.into()),
+        };
+        // Only give up on schemas if the same prompt works without one
+        let res = self.ollama.generate(self.request(prompt)).await?;
+        if self.structured_output.swap(false, Ordering::Relaxed) {
+            slog_scope::warn!("Ollama rejected the JSON schema ({}), falling back to extracting JSON from the answer. Set OLLAMA_STRUCTURED_OUTPUT=false to skip the schema", error);
+        }
+        Ok(LlmResponse { response: res.response })
+    }
+
+    async fn check(&self) -> Result<(), LlmError> {
+        let models = self.ollama.list_local_models().await?;
+        // Models pulled without a tag are listed as `<name>:latest`
+        let latest = format!("{}:latest", self.model);
+        if models.iter().any(|model| model.name == self.model || model.name == latest) {
+            Ok(())
+        } else {
+            Err(Box::new(ResponseError::Other(format!("model {} is not available, pull it with `ollama pull {}`", self.model, self.model))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_format() {
+        let old_release = r#"{"error":"json: cannot unmarshal object into Go struct field GenerateRequest.format of type string"}"#;
+        assert!(rejects_format(&OllamaError::Other(old_release.to_string())));
+        assert!(!rejects_format(&OllamaError::Other(r#"{"error":"model requires more system memory"}"#.to_string())));
+        assert!(!rejects_format(&OllamaError::Other("Failed to read response: connection reset".to_string())));
+    }
+}
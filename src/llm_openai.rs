@@ -0,0 +1,133 @@
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::llm_api::{ChatMessage, ChatRole, LlmBackend, LlmError, LlmResponse, ModelInfo};
+use crate::error::ResponseError;
+
+/// Backend for servers exposing the OpenAI compatible `/v1/chat/completions`
+/// endpoint, e.g. vLLM or the llama.cpp server.
+pub struct OpenAiBackend {
+    client: Client,
+    base_url: String,
+    api_key: Option<String>,
+    model: String,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+struct CompletionMessage {
+    role: &'static str,
+    content: String,
+}
+
+#[derive(Serialize, Debug)]
+struct CompletionRequest<'a> {
+    model: &'a str,
+    messages: Vec<CompletionMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionResponse {
+    choices: Vec<CompletionChoice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionChoice {
+    message: CompletionChoiceMessage,
+}
+
+#[derive(Deserialize, Debug)]
+struct CompletionChoiceMessage {
+    content: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ModelList {
+    data: Vec<ModelEntry>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ModelEntry {
+    id: String,
+}
+
+impl OpenAiBackend {
+    pub fn new(base_url: &str, api_key: Option<String>, model: &str, temperature: Option<f32>, max_tokens: Option<u32>) -> Self {
+        OpenAiBackend {
+            client: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            api_key,
+            model: model.to_string(),
+            temperature,
+            max_tokens,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OpenAiBackend {
+    fn model_info(&self) -> ModelInfo {
+        ModelInfo {
+            backend: "openai",
+            name: self.model.clone(),
+            structured_output: false,
+        }
+    }
+
+    async fn generate(&self, prompt: String, schema: Option<&Value>) -> Result<LlmResponse, LlmError> {
+        self.chat(vec![ChatMessage::new(ChatRole::User, prompt)], schema).await
+    }
+
+    async fn chat(&self, messages: Vec<ChatMessage>, _schema: Option<&Value>) -> Result<LlmResponse, LlmError> {
+        let request = CompletionRequest {
+            model: &self.model,
+            messages: messages
+                .into_iter()
+                .map(|message| CompletionMessage {
+                    role: match message.role {
+                        ChatRole::System => "system",
+                        ChatRole::User => "user",
+                    },
+                    content: message.content,
+                })
+                .collect(),
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+        };
+
+        let url = format!("{}/chat/completions", self.base_url);
+        let mut builder = self.client.post(&url).json(&request);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+        let res = builder.send().await?.error_for_status()?;
+        let body = res.text().await?;
+        slog_scope::trace!("Response from {}: {}", url, body);
+
+        let completion: CompletionResponse = serde_json::from_str(&body)?;
+        match completion.choices.into_iter().next().and_then(|choice| choice.message.content) {
+            Some(response) => Ok(LlmResponse { response }),
+            None => Err(Box::new(ResponseError::Other("completion response contained no message".to_string()))),
+        }
+    }
+
+    async fn check(&self) -> Result<(), LlmError> {
+        let url = format!("{}/models", self.base_url);
+        let mut builder = self.client.get(&url);
+        if let Some(api_key) = &self.api_key {
+            builder = builder.bearer_auth(api_key);
+        }
+        let models: ModelList = builder.send().await?.error_for_status()?.json().await?;
+        if models.data.iter().any(|model| model.id == self.model) {
+            Ok(())
+        } else {
+            Err(Box::new(ResponseError::Other(format!("model {} is not served by {}", self.model, self.base_url))))
+        }
+    }
+}
@@ -1,9 +1,5 @@
 use lazy_static::lazy_static;
 use slog::{Drain, Logger, Level, o};
-use slog_async;
-use slog_term;
-use slog_scope;
-use slog_stdlog;
 use std::env;
 use std::sync::Mutex;
 
@@ -13,10 +9,12 @@ lazy_static! {
         let decorator = slog_term::TermDecorator::new().stdout().build();
         let drain = slog_term::FullFormat::new(decorator).build().fuse();
         let drain = slog::LevelFilter::new(drain, level).fuse();
-        let drain = slog_async::Async::new(drain).build().fuse();
-        Logger::root(drain, o!())
+        let (drain, guard) = slog_async::Async::new(drain).build_with_guard();
+        *ASYNC_GUARD.lock().unwrap() = Some(guard);
+        Logger::root(drain.fuse(), o!())
     };
     static ref LOGGER_GUARD: Mutex<Option<slog_scope::GlobalLoggerGuard>> = Mutex::new(None);
+    static ref ASYNC_GUARD: Mutex<Option<slog_async::AsyncGuard>> = Mutex::new(None);
 
 }
 
@@ -37,4 +35,11 @@ pub fn init() {
     slog_stdlog::init().unwrap();
     let mut guard_store = LOGGER_GUARD.lock().unwrap();
     *guard_store = Some(guard);
-}
\ No newline at end of file
+}
+/// Writes out pending log messages, must be called before `std::process::exit`.
+/// Nothing may be logged afterwards.
+pub fn flush() {
+    if let Some(guard) = ASYNC_GUARD.lock().unwrap().take() {
+        drop(guard);
+    }
+}
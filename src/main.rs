@@ -1,13 +1,32 @@
 mod llm_api;
+mod llm_ollama;
+mod llm_openai;
 mod paperless;
 mod logger;
 mod paperless_defaultfields;
 mod util;
 mod error;
-
-use ollama_rs::{
-    Ollama,
-};
+mod schema;
+mod json_repair;
+mod field_values;
+mod marker;
+mod document_update;
+mod dry_run;
+mod cli;
+mod commands;
+mod config;
+mod daemon;
+mod shutdown;
+mod webhook;
+mod post_consume;
+mod limits;
+mod state;
+mod review;
+mod prompt;
+mod chunking;
+mod language;
+#[cfg(test)]
+mod test_support;
 
 use reqwest::{Client};
 use std::result::Result;
@@ -16,12 +35,34 @@ use std::result::Result;
 //write function that queries a rest endpoint for a given url
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
 use serde::{Deserialize, Serialize};
-use serde_json::{Value};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
 use std::env;
-use crate::llm_api::generate_response;
-use crate::paperless::{get_data_from_paperless, get_default_fields, get_next_data_from_paperless, query_custom_fields, update_document_fields, PaperlessDefaultFieldType};
-use substring::Substring;
-use crate::paperless_defaultfields::extract_default_fields;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::llm_api::{chat_response, ChatMessage, ChatRole, LlmBackend};
+use crate::llm_ollama::OllamaBackend;
+use crate::llm_openai::OpenAiBackend;
+use crate::error::ResponseError;
+use crate::paperless::{document_matches_query, get_data_from_paperless, get_document, get_next_data_from_paperless, query_custom_fields, DefaultFieldOptions, PaperlessDefaultFieldType};
+use crate::paperless_defaultfields::suggest_default_fields;
+use crate::schema::custom_fields_schema;
+use crate::json_repair::{parse_json, Repair};
+use crate::marker::Marker;
+use crate::config::{Config, LlmConfig};
+use crate::shutdown::Shutdown;
+use crate::document_update::DocumentUpdate;
+use crate::dry_run::ObjectNames;
+use crate::cli::{Cli, Command, ReviewAction};
+use crate::limits::{LimitedLlm, Limits};
+use crate::review::ReviewQueue;
+use crate::language::{detect_language, prompt_language, PROMPT_LANGUAGES};
+use crate::chunking::{merge_candidates, reduce_prompt, Chunking};
+use crate::prompt::{default_prompt, describe_fields, PromptProfile, PromptTemplate};
+use crate::state::{Journal, Record, Selection, StateStore, Status};
+use clap::Parser;
+use futures::stream::{self, StreamExt};
+use tokio::sync::{Mutex, OnceCell};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct Document {
@@ -65,21 +106,47 @@ struct Field {
     id: u32,
     name: String,
     data_type: String,
+    #[serde(default)]
+    extra_data: Option<FieldExtraData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct FieldExtraData {
+    #[serde(default)]
+    select_options: Vec<SelectOption>,
+    default_currency: Option<String>,
+}
+
+// Paperless before 2.14 stores select options as plain labels referenced by index
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum SelectOption {
+    Label(String),
+    Option { id: String, label: String },
+}
+
+impl SelectOption {
+    fn label(&self) -> &str {
+        match self {
+            SelectOption::Label(label) => label,
+            SelectOption::Option { label, .. } => label,
+        }
+    }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 enum Mode {
     NoAnalyze,
     Create,
     NoCreate,
 }
 impl Mode {
-    fn from_int(value: i32) -> Self {
-        match value {
-            2 => Mode::Create,
-            1 => Mode::NoCreate,
-            0 => Mode::NoAnalyze,
-            _ => Mode::NoCreate,
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_ref() {
+            "2" | "create" => Some(Mode::Create),
+            "1" | "no_create" => Some(Mode::NoCreate),
+            "0" | "no_analyze" => Some(Mode::NoAnalyze),
+            _ => None,
         }
     }
 }
@@ -98,226 +165,726 @@ fn init_paperless_client(token: &str) -> Client {
         .expect("Failed to build client")
 }
 
-// Initialize Ollama client
-fn init_ollama_client(host: &str, port: u16, secure_endpoint: bool) -> Ollama {
-    let protocol = if secure_endpoint { "https" } else { "http" };
-    let ollama_base_url = format!("{}://{}", protocol, host);
-    Ollama::new(ollama_base_url, port)
-}
-
-// Refactor the main process into a function for better readability
-async fn process_documents(client: &Client, ollama: &Ollama, model: &str, base_url: &str, filter: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let language = env::var("LANGUAGE").unwrap_or_else(|_| "EN".to_string()).to_uppercase();
-    let base_prompt;
-
-    match language.as_ref() {
-        "DE" => base_prompt = "Bitte ziehe die Metadaten aus dem bereitgestelltem Dokument \
-        und antworte im JSON format. \
-        Die Felder, welche ich brauche sind:\
-         title,topic,sender,recipient,urgency(mit werten entweder n/a oder low oder medium oder high),\
-         date_received(im maschinenlesbarem format),category.\
-         Analysiere das Dokument, um die Werte für diese Felder zu finden und forme die Antwort als JSON-Objekt. \
-         Verwende die wahrscheinlichste Antwort für jedes Feld in der gleichen Sprache wie das Dokument. \
-         Die Antwort sollte nur JSON-Daten enthalten, bei denen die Schlüssel und Werte alle in einfacher Textform \
-         (keine verschachtelten Objekte) vorliegen, um von einem anderen Programm direkt analysiert werden zu können. \
-         Also keine zusätzlichen Texte oder Erklärungen, der Antworttext sollte mit eckigen Klammern beginnen und enden, \
-         die das JSON-Objekt umfassen ".to_string(),
-        _ => base_prompt = "Please extract metadata\
-        from the provided document and return it in JSON format.\
-        The fields I need are:\
-         title,topic,sender,recipient,urgency(with value either n/a or low or medium or high),\
-         date_received(in machine-readable format),category.\
-          Analyze the document to find the values for these fields and format the response as a \
-          JSON object. Use the most likely answer for each field. \
-          The response should contain only JSON data where the key and values are all in simple string \
-          format(no nested object) for direct parsing by another program. So no additional text or \
-          explanation, no introtext, the answer should start and end with curly brackets \
-          delimiting the json object ".to_string()
-    };
+// Initialize the LLM backend selected in the configuration
+fn init_llm_backend(config: &LlmConfig, context_size: Option<usize>) -> Box<dyn LlmBackend> {
+    match config {
+        LlmConfig::Ollama { host, port, secure_endpoint, model, structured_output } => {
+            Box::new(OllamaBackend::new(host, *port, *secure_endpoint, model, *structured_output, context_size))
+        }
+        LlmConfig::OpenAi { base_url, api_key, model, temperature, max_tokens } => {
+            Box::new(OpenAiBackend::new(base_url, api_key.clone(), model, *temperature, *max_tokens))
+        }
+    }
+}
 
-    let prompt_base = env::var("BASE_PROMPT").unwrap_or_else(|_| base_prompt.to_string());
-
-    let mode_env = env::var("MODE").unwrap_or_else(|_| "0".to_string());
-    let mode_int = mode_env.parse::<i32>().unwrap_or(0);
-    let mode = Mode::from_int(mode_int);
-    let fields = query_custom_fields(client, base_url).await?;
-    match get_data_from_paperless(&client, &base_url, filter).await {
-        Ok(mut data) => {
-            loop {
-                process_documents_batch(&data.results, ollama, model, &prompt_base, client, &fields, base_url, mode).await?;
-
-                if let Some(url) = data.next {
-                    match get_next_data_from_paperless(&client, url.as_str()).await {
-                        Ok(next_data) => {
-                            data = next_data;
-                        }
-                        Err(e) => {
-                            slog_scope::error!("Error while interacting with paperless: {}", e);
-                            break;
-                        }
-                    }
-                } else {
-                    break;
-                }
+/// Which documents that were processed before are processed again.
+#[derive(Clone, Copy, Debug)]
+enum Reprocess {
+    /// Skip marked documents and documents unchanged since their last result
+    Never,
+    /// Process every selected document
+    Always,
+    /// Process documents whose content, model or prompt changed since their
+    /// last result, or that have no result, at most `limit` per run
+    Stale { limit: Option<usize> },
+}
+
+/// Settings and connections shared by every document processed in a run.
+struct ProcessingContext<'a> {
+    client: &'a Client,
+    base_url: &'a str,
+    llm: &'a dyn LlmBackend,
+    prompt: PromptTemplate,
+    /// Built-in prompt per language, set if the language is detected per document
+    translations: Vec<(&'static str, PromptTemplate)>,
+    /// Custom field the detected language is stored in
+    language_field: Option<String>,
+    profiles: &'a [PromptProfile],
+    chunking: Chunking,
+    /// Tags, document types and correspondents listed in the prompts or
+    /// matched by the profiles, loaded if needed
+    prompt_names: Option<ObjectNames>,
+    mode: Mode,
+    tag_options: DefaultFieldOptions,
+    doctype_options: DefaultFieldOptions,
+    correspondent_options: DefaultFieldOptions,
+    field_type_overrides: HashMap<String, String>,
+    field_descriptions: HashMap<String, String>,
+    marker: Marker,
+    /// Set in a dry run, changes are reported instead of written
+    dry_run: Option<ObjectNames>,
+    reprocess: Reprocess,
+    /// Documents that may still be processed with `Reprocess::Stale`
+    remaining: Option<AtomicUsize>,
+    shutdown: Shutdown,
+    limits: Limits,
+    /// Records the results, set if `state.path` is configured
+    state: Option<StateStore>,
+    /// Hash of the prompts, the profiles and the custom fields they list,
+    /// stored with every result
+    prompt_version: String,
+    /// Id under which the changes of this run are journaled, the run is
+    /// started with the first change
+    run_id: OnceCell<i64>,
+    /// Set if some documents need approval before their changes are applied
+    review: Option<ReviewQueue>,
+}
+
+impl<'a> ProcessingContext<'a> {
+    async fn new(
+        client: &'a Client,
+        llm: &'a dyn LlmBackend,
+        config: &'a Config,
+        fields: &mut Vec<Field>,
+        dry_run: bool,
+        reprocess: Reprocess,
+        shutdown: Shutdown,
+    ) -> Result<ProcessingContext<'a>, Box<dyn std::error::Error>> {
+        let base_url = config.base_url.as_str();
+        let prompt = match &config.prompt_template {
+            Some(prompt) => prompt.clone(),
+            None => default_prompt(&config.language),
+        };
+        let translations = match (config.language.as_str(), &config.prompt_template) {
+            ("AUTO", None) => PROMPT_LANGUAGES.iter().map(|language| (*language, default_prompt(language))).collect(),
+            _ => Vec::new(),
+        };
+        let state = match &config.state_db {
+            Some(path) => Some(StateStore::open(path)?),
+            None => None,
+        };
+        let remaining = match reprocess {
+            Reprocess::Stale { .. } if state.is_none() => {
+                return Err(ResponseError::Other("reprocessing stale documents requires state.path, set DOCLYTICS_STATE_DB".to_string()).into())
+            }
+            Reprocess::Stale { limit } => limit.map(AtomicUsize::new),
+            Reprocess::Never | Reprocess::Always => None,
+        };
+
+        let marker = Marker::bootstrap(client, base_url, &config.marker, fields, dry_run).await?;
+        // A prompt listing the custom fields changes with them
+        let templates: Vec<&PromptTemplate> = std::iter::once(&prompt)
+            .chain(translations.iter().map(|(_, translation)| translation))
+            .chain(config.profiles.iter().filter_map(|profile| profile.prompt.as_ref()))
+            .collect();
+        let mut versioned = prompt.source().to_string();
+        for (_, translation) in &translations {
+            versioned = format!("{}\n{}", versioned, translation.source());
+        }
+        if templates.iter().any(|template| template.uses("custom_fields")) {
+            versioned = format!("{}\n{}", versioned, describe_fields(fields, marker.field_id(), &config.field_descriptions));
+        }
+        if !config.profiles.is_empty() {
+            versioned = format!("{}\n{:?}", versioned, config.profiles);
+        }
+        let prompt_version = state::hash(&versioned);
+        let review = ReviewQueue::load(client, base_url, &config.review).await?;
+        if let Some(field) = &config.language_field {
+            if !matches!(config.mode, Mode::Create) && !fields.iter().any(|known| known.name == *field) {
+                slog_scope::warn!("Custom field {} for the language does not exist, set MODE=2 to create it", field);
             }
         }
-        Err(e) => slog_scope::error!("Error while interacting with paperless: {}", e),
+        let lists_names = templates.iter().any(|template| ["existing_tags", "document_types", "correspondents"].iter().any(|name| template.uses(name)));
+        let prompt_names = match lists_names || !config.profiles.is_empty() {
+            true => Some(ObjectNames::load(client, base_url).await?),
+            false => None,
+        };
+        let dry_run = match dry_run {
+            true => Some(ObjectNames::load(client, base_url).await?),
+            false => None,
+        };
+        Ok(ProcessingContext {
+            client,
+            base_url,
+            llm,
+            prompt,
+            translations,
+            language_field: config.language_field.clone(),
+            profiles: &config.profiles,
+            chunking: config.chunking,
+            prompt_names,
+            mode: config.mode,
+            tag_options: config.tags,
+            doctype_options: config.document_type,
+            correspondent_options: config.correspondent,
+            field_type_overrides: config.field_types.clone(),
+            field_descriptions: config.field_descriptions.clone(),
+            marker,
+            dry_run,
+            reprocess,
+            remaining,
+            shutdown,
+            limits: Limits::new(config.llm_concurrency, config.paperless_concurrency),
+            state,
+            prompt_version,
+            run_id: OnceCell::new(),
+            review,
+        })
+    }
+
+    /// Takes one document from the limit of `Reprocess::Stale`, `false` if
+    /// the limit is reached.
+    fn take_slot(&self) -> bool {
+        match &self.remaining {
+            Some(remaining) => remaining.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1)).is_ok(),
+            None => true,
+        }
+    }
+
+    /// Whether no more documents are started, because of a shutdown or the
+    /// limit of `Reprocess::Stale`.
+    fn is_done(&self) -> bool {
+        self.shutdown.is_requested() || self.remaining.as_ref().is_some_and(|remaining| remaining.load(Ordering::SeqCst) == 0)
+    }
+
+    /// The journal of this run, `None` without state database. The run is
+    /// only started once a document is changed, so a daemon poll without
+    /// new documents does not add an empty run.
+    async fn journal(&self) -> Result<Option<Journal<'_>>, Box<dyn std::error::Error>> {
+        let Some(state) = &self.state else {
+            return Ok(None);
+        };
+        let run_id = self
+            .run_id
+            .get_or_try_init(|| async {
+                let run_id = state.start_run()?;
+                slog_scope::info!("Started run {}", run_id);
+                Ok::<_, Box<dyn std::error::Error>>(run_id)
+            })
+            .await?;
+        Ok(Some(state.journal(*run_id)))
+    }
+
+    /// The LLM, limited to the configured number of concurrent requests.
+    fn llm(&self) -> LimitedLlm<'_> {
+        self.limits.llm(self.llm)
     }
-    Ok(())
 }
 
-async fn process_documents_batch(documents: &Vec<Document>, ollama: &Ollama, model: &str, prompt_base: &String, client: &Client, fields: &Vec<Field>, base_url: &str, mode: Mode) -> Result<(), Box<dyn std::error::Error>> {
-    let tag_mode = create_mode_from_env("DOCLYTICS_TAGS");
-    let doctype_mode = create_mode_from_env("DOCLYTICS_DOCTYPE");
-    let correspondent_mode = create_mode_from_env("DOCLYTICS_CORRESPONDENT");
-
-    Ok(for document in documents {
-        slog_scope::trace!("Document Content: {}", document.content);
-        slog_scope::info!("Generate Response with LLM {}", "model");
-        slog_scope::debug!("with Prompt: {}", prompt_base);
-
-        generate_response_and_extract_data(ollama, &model, &prompt_base, client, &fields, base_url, mode, &document).await;
-        let default_fields = get_default_fields(client, base_url, PaperlessDefaultFieldType::Tag).await;
-        match default_fields {
-            Ok(default_fields) => {
-                match tag_mode {
-                    Mode::NoAnalyze => (),
-                    _ =>
-                        if let Some(err) = extract_default_fields(ollama, &model, &prompt_base, client, &default_fields, base_url, &document, tag_mode, PaperlessDefaultFieldType::Tag).await {
-                            slog_scope::error!("Error while getting tags: {:?}", err);
-                        }
-                }
-                match doctype_mode {
-                    Mode::NoAnalyze => (),
-                    _ =>
-                        if let Some(err) = extract_default_fields(ollama, &model, &prompt_base, client, &default_fields, base_url, &document, doctype_mode, PaperlessDefaultFieldType::DocumentType).await {
-                            slog_scope::error!("Error while getting doctype: {:?}", err);
-                        }
-                }
-                match correspondent_mode {
-                    Mode::NoAnalyze => (),
-                    _ => if let Some(err) = extract_default_fields(ollama, &model, &prompt_base, client, &default_fields, base_url, &document, correspondent_mode, PaperlessDefaultFieldType::Correspondent).await {
-                        slog_scope::error!("Error while getting correspondents: {:?}", err);
+async fn process_documents(context: &ProcessingContext<'_>, fields: &mut Vec<Field>, filter: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let client = context.client;
+    let permit = context.limits.paperless().await;
+    let mut data = match get_data_from_paperless(client, context.base_url, filter).await {
+        Ok(data) => data,
+        Err(e) => {
+            slog_scope::error!("Error while interacting with paperless: {}", e);
+            return Err(e);
+        }
+    };
+    drop(permit);
+    loop {
+        process_documents_batch(&data.results, context, fields).await?;
+
+        match data.next {
+            Some(url) if !context.is_done() => {
+                let _permit = context.limits.paperless().await;
+                match get_next_data_from_paperless(client, url.as_str()).await {
+                    Ok(next_data) => data = next_data,
+                    Err(e) => {
+                        slog_scope::error!("Error while interacting with paperless: {}", e);
+                        return Err(e);
                     }
                 }
             }
-            Err(e) => slog_scope::error!("Error while interacting with paperless: {}", e),
+            _ => return Ok(()),
         }
-    })
+    }
 }
 
-async fn generate_response_and_extract_data(ollama: &Ollama, model: &str, prompt_base: &String, client: &Client, fields: &Vec<Field>, base_url: &str, mode: Mode, document: &Document) {
-    let prompt = format!("{} {}", prompt_base, document.content);
+async fn process_document_ids(context: &ProcessingContext<'_>, fields: &mut Vec<Field>, document_ids: &[u32]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut documents = Vec::new();
+    for document_id in document_ids {
+        let _permit = context.limits.paperless().await;
+        documents.push(get_document(context.client, context.base_url, *document_id).await?);
+    }
+    process_documents_batch(&documents, context, fields).await
+}
 
-    match generate_response(ollama, &model.to_string(), prompt).await {
-        Ok(res) => {
-            // Log the response from the generate_response call
-            slog_scope::debug!("LLM Response: {}", res.response);
+/// Processes up to `limits.documents` documents at the same time. New custom
+/// fields created for one document are visible to the others.
+async fn process_documents_batch(documents: &[Document], context: &ProcessingContext<'_>, fields: &mut Vec<Field>) -> Result<(), Box<dyn std::error::Error>> {
+    let shared = Mutex::new(std::mem::take(fields));
+    let shared_fields = &shared;
+    let pending = documents.iter().take_while(|_| !context.is_done());
+    stream::iter(pending)
+        .map(|document| async move {
+            match process_document(context, shared_fields, document).await {
+                Outcome::AnalysisFailed(e) => slog_scope::error!("Document {} not updated: {}", document.id, e),
+                Outcome::UpdateFailed(e) => slog_scope::error!("Document {} not marked as processed: {}", document.id, e),
+                Outcome::Skipped | Outcome::Updated | Outcome::Reported | Outcome::Queued => {}
+            }
+        })
+        .buffer_unordered(context.limits.documents)
+        .collect::<()>()
+        .await;
+    *fields = shared.into_inner();
+    Ok(())
+}
 
-            match extract_json_object(&res.response) {
-                Ok(json_str) => {
-                    // Log successful JSON extraction
-                    slog_scope::debug!("Extracted JSON Object: {}", json_str);
+/// What happened to a single document.
+enum Outcome {
+    /// Already marked as processed
+    Skipped,
+    Updated,
+    /// Changes were printed in a dry run
+    Reported,
+    /// Changes wait for approval in the review queue
+    Queued,
+    AnalysisFailed(Box<dyn std::error::Error>),
+    UpdateFailed(Box<dyn std::error::Error>),
+}
 
-                    match serde_json::from_str(&json_str) {
-                        Ok(json) => update_document_fields(client, document.id, &fields, &json, base_url, mode).await.unwrap_or_default(), //TODO: Fix unwrap
-                        Err(e) => {
-                            slog_scope::error!("Error parsing llm response json {}", e.to_string());
-                            slog_scope::debug!("JSON String was: {}", &json_str);
-                        }
-                    }
+async fn process_document(context: &ProcessingContext<'_>, fields: &Mutex<Vec<Field>>, document: &Document) -> Outcome {
+    if matches!(context.reprocess, Reprocess::Never) && context.marker.is_marked(document) {
+        slog_scope::debug!("Document {} is already processed, skipping", document.id);
+        return Outcome::Skipped;
+    }
+    let started_at = chrono::Utc::now().to_rfc3339();
+    let content_hash = state::hash(&document.content);
+    let model = context.llm.model_info().name;
+    if !matches!(context.reprocess, Reprocess::Always) && is_unchanged(context, document.id, &content_hash, &model) {
+        slog_scope::info!("Document {} was already processed with the same content, model and prompt, skipping", document.id);
+        return Outcome::Skipped;
+    }
+    if !matches!(context.reprocess, Reprocess::Always) && awaits_reset(context, document.id) {
+        slog_scope::info!("Document {} was rolled back, skipping until its marker is reset", document.id);
+        return Outcome::Skipped;
+    }
+    if !matches!(context.reprocess, Reprocess::Always) && has_pending_proposal(context, document.id) {
+        slog_scope::info!("Document {} waits for review, skipping", document.id);
+        return Outcome::Skipped;
+    }
+    if !context.take_slot() {
+        return Outcome::Skipped;
+    }
+    slog_scope::trace!("Document Content: {}", document.content);
+    slog_scope::info!("Generate Response with LLM {}", model);
+
+    let mut record = Record {
+        document_id: document.id,
+        content_hash,
+        model,
+        prompt_version: context.prompt_version.clone(),
+        raw_response: Value::Object(Map::new()),
+        parsed_result: Value::Object(Map::new()),
+        applied_changes: None,
+        status: Status::AnalysisFailed,
+        error: None,
+        started_at,
+        finished_at: String::new(),
+        repairs: Value::Object(Map::new()),
+    };
+    let known_fields = fields.lock().await.clone();
+    let mut analysis = match analyze_document(context, &known_fields, document).await {
+        Ok(analysis) => analysis,
+        Err(e) => {
+            if context.dry_run.is_none() {
+                record.error = Some(e.to_string());
+                save_record(context, record);
+            }
+            return Outcome::AnalysisFailed(e);
+        }
+    };
+    if let Some(names) = &context.dry_run {
+        println!("{}", dry_run::report(&analysis.update, document, &known_fields, names));
+        return Outcome::Reported;
+    }
+    let (outcome, status, applied_changes, error) = match (&context.review, &context.state) {
+        (Some(review), Some(state)) if review.requires_review(&analysis.update, document) => match state.add_proposal(document, &analysis.update) {
+            Ok(proposal_id) => {
+                slog_scope::info!("Changes for document {} wait for review as proposal {}", document.id, proposal_id);
+                (Outcome::Queued, Status::Queued, None, None)
+            }
+            Err(e) => return Outcome::UpdateFailed(e),
+        },
+        _ => {
+            // All writes of a document are sent one after another
+            let permit = context.limits.paperless().await;
+            let result = match context.journal().await {
+                Ok(journal) => analysis.update.apply(context.client, context.base_url, document, fields, &context.marker, journal.as_ref()).await,
+                Err(e) => Err(e),
+            };
+            drop(permit);
+            match result {
+                Ok(payload) => (Outcome::Updated, Status::Updated, Some(Value::Object(payload)), None),
+                Err(e) => {
+                    let error = e.to_string();
+                    (Outcome::UpdateFailed(e), Status::UpdateFailed, None, Some(error))
                 }
-                Err(e) => slog_scope::error!("{}", e),
             }
         }
+    };
+
+    save_record(context, Record {
+        raw_response: Value::Object(analysis.responses),
+        parsed_result: Value::Object(analysis.parsed),
+        repairs: Value::Object(analysis.repairs),
+        applied_changes,
+        status,
+        error,
+        ..record
+    });
+    outcome
+}
+
+fn save_record(context: &ProcessingContext<'_>, mut record: Record) {
+    let Some(state) = &context.state else {
+        return;
+    };
+    record.finished_at = chrono::Utc::now().to_rfc3339();
+    if let Err(e) = state.record(&record) {
+        slog_scope::warn!("Could not record the result of document {}: {}", record.document_id, e);
+    }
+}
+
+/// Whether the last result of the document was produced from the same
+/// content, model and prompt and was not rolled back.
+fn is_unchanged(context: &ProcessingContext<'_>, document_id: u32, content_hash: &str, model: &str) -> bool {
+    let Some(state) = &context.state else {
+        return false;
+    };
+    let unchanged = state.last_result(document_id, Status::Updated).and_then(|last| match last {
+        Some(last) if last.content_hash == content_hash && last.model == model && last.prompt_version == context.prompt_version => {
+            // A rolled back document is processed again once it is reset
+            state.is_rolled_back(document_id).map(|rolled_back| !rolled_back)
+        }
+        _ => Ok(false),
+    });
+    match unchanged {
+        Ok(unchanged) => unchanged,
         Err(e) => {
-            slog_scope::error!("Error generating llm response: {}", e);
+            slog_scope::warn!("Could not read the state of document {}: {}", document_id, e);
+            false
         }
     }
 }
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    logger::init(); // Initializes the global logger
-    slog_scope::info!("Application started, version: {}", env!("CARGO_PKG_VERSION"));
-    let token = env::var("PAPERLESS_TOKEN").expect("PAPERLESS_TOKEN is not set in .env file");
-    let base_url = env::var("PAPERLESS_BASE_URL").expect("PAPERLESS_BASE_URL is not set in .env file");
-    let client = init_paperless_client(&token);
 
-    let ollama_host = env::var("OLLAMA_HOST").unwrap_or_else(|_| "localhost".to_string());
-    let ollama_port = env::var("OLLAMA_PORT")
-        .unwrap_or_else(|_| "11434".to_string())
-        .parse::<u16>().unwrap_or(11434);
-    let ollama_secure_endpoint = env::var("OLLAMA_SECURE_ENDPOINT")
-        .unwrap_or_else(|_| "false".to_string())
-        .parse::<bool>().unwrap_or(false);
+fn awaits_reset(context: &ProcessingContext<'_>, document_id: u32) -> bool {
+    let Some(state) = &context.state else {
+        return false;
+    };
+    state.awaits_reset(document_id).unwrap_or_else(|e| {
+        slog_scope::warn!("Could not read the state of document {}: {}", document_id, e);
+        false
+    })
+}
 
-    let ollama = init_ollama_client(&ollama_host, ollama_port, ollama_secure_endpoint);
+fn has_pending_proposal(context: &ProcessingContext<'_>, document_id: u32) -> bool {
+    let Some(state) = &context.state else {
+        return false;
+    };
+    state.has_pending_proposal(document_id).unwrap_or_else(|e| {
+        slog_scope::warn!("Could not read the proposals of document {}: {}", document_id, e);
+        false
+    })
+}
 
-    let model = env::var("OLLAMA_MODEL").unwrap_or_else(|_| "llama2:13b".to_string());
+/// The changes proposed for a document and the LLM answers they are based on.
+struct Analysis {
+    update: DocumentUpdate,
+    /// Raw answer per prompt: `custom_fields`, `tags`, `document_type` and `correspondent`
+    responses: Map<String, Value>,
+    /// Parsed answer per prompt, same keys as `responses`
+    parsed: Map<String, Value>,
+    /// Repairs applied to the answer of each prompt, only prompts whose
+    /// answer needed any
+    repairs: Map<String, Value>,
+}
 
-    let default_filter = env::var("PAPERLESS_FILTER").unwrap_or_else(|_| "NOT tagged=true".to_string());
+impl Analysis {
+    fn add_repairs(&mut self, key: &str, repairs: &[Repair]) {
+        if !repairs.is_empty() {
+            self.repairs.insert(key.to_string(), repairs.iter().map(|repair| Value::String(repair.to_string())).collect());
+        }
+    }
+}
 
-    process_documents(&client, &ollama, &model, &base_url, default_filter.as_str()).await
+/// Collects every change the LLM proposes for the document. The prompts for
+/// the custom fields, tags, document type and correspondent are sent
+/// concurrently. With prompt profiles the custom fields are extracted after
+/// the classification, which selects the profile. If any part of the
+/// analysis fails the document is left untouched.
+async fn analyze_document(context: &ProcessingContext<'_>, fields: &[Field], document: &Document) -> Result<Analysis, Box<dyn std::error::Error>> {
+    let language = match !context.translations.is_empty() || context.language_field.is_some() {
+        true => detect_language(&document.content),
+        false => None,
+    };
+    let (profile, (mut json, response, repairs), mut analysis) = match context.profiles.is_empty() {
+        true => {
+            let (custom_fields, analysis) = tokio::try_join!(analyze_custom_fields(context, fields, document, None, language), classify_document(context, document))?;
+            (None, custom_fields, analysis)
+        }
+        false => {
+            let analysis = classify_document(context, document).await?;
+            let profile = select_profile(context, document, &analysis.update).await?;
+            (profile, analyze_custom_fields(context, fields, document, profile, language).await?, analysis)
+        }
+    };
+
+    if let Some(profile) = profile {
+        json.retain(|key, _| key == "title" || profile.includes(key));
+        analysis.parsed.insert("profile".to_string(), Value::String(profile.name.clone()));
+    }
+    if let Some(language) = language {
+        slog_scope::debug!("Document {} is written in {}", document.id, language.eng_name());
+        analysis.parsed.insert("language".to_string(), Value::String(language.eng_name().to_string()));
+        if let Some(field) = &context.language_field {
+            json.insert(field.clone(), Some(Value::String(language.eng_name().to_string())));
+        }
+    }
+    analysis.update.add_custom_fields(fields, &json, context.mode, context.marker.field_id(), &context.field_type_overrides);
+    analysis.responses.insert("custom_fields".to_string(), response);
+    analysis.add_repairs("custom_fields", &repairs);
+    analysis.parsed.insert("custom_fields".to_string(), serde_json::to_value(&json)?);
+    Ok(analysis)
 }
 
-fn extract_json_object(input: &str) -> Result<String, String> {
-    let mut brace_count = 0;
-    let mut json_start = None;
-    let mut json_end = None;
+/// Suggests the tags, document type and correspondent.
+async fn classify_document(context: &ProcessingContext<'_>, document: &Document) -> Result<Analysis, Box<dyn std::error::Error>> {
+    let (tags, document_type, correspondent) = tokio::try_join!(
+        suggest_default_fields(context, document, context.tag_options, PaperlessDefaultFieldType::Tag),
+        suggest_default_fields(context, document, context.doctype_options, PaperlessDefaultFieldType::DocumentType),
+        suggest_default_fields(context, document, context.correspondent_options, PaperlessDefaultFieldType::Correspondent),
+    )?;
+
+    let mut analysis = Analysis { update: DocumentUpdate::new(document.id), responses: Map::new(), parsed: Map::new(), repairs: Map::new() };
+    let suggestions = [
+        ("tags", context.tag_options, PaperlessDefaultFieldType::Tag, tags),
+        ("document_type", context.doctype_options, PaperlessDefaultFieldType::DocumentType, document_type),
+        ("correspondent", context.correspondent_options, PaperlessDefaultFieldType::Correspondent, correspondent),
+    ];
+    for (key, options, field_type, suggestion) in suggestions {
+        if let Some(suggestion) = suggestion {
+            analysis.responses.insert(key.to_string(), Value::String(suggestion.response));
+            analysis.parsed.insert(key.to_string(), serde_json::to_value(&suggestion.objects)?);
+            analysis.add_repairs(key, &suggestion.repairs);
+            analysis.update.merge_default_field(document, field_type, options.merge, suggestion.objects, &suggestion.known);
+        }
+    }
+    Ok(analysis)
+}
 
-    for (i, c) in input.chars().enumerate() {
-        match c {
-            '{' | '[' => {
-                if brace_count == 0 {
-                    json_start = Some(i);
-                }
-                brace_count += 1;
+/// The first profile matching the proposed or current document type and
+/// tags, or whose query finds the document.
+async fn select_profile<'c>(
+    context: &'c ProcessingContext<'_>,
+    document: &Document,
+    update: &DocumentUpdate,
+) -> Result<Option<&'c PromptProfile>, Box<dyn std::error::Error>> {
+    let name = |field_type, id| context.prompt_names.as_ref().map_or_else(|| format!("#{}", id), |names| names.name(field_type, id));
+    let document_type = match &update.document_type {
+        Some(document_type) => Some(document_type.name.clone()),
+        None => document.document_type.map(|id| name(PaperlessDefaultFieldType::DocumentType, id)),
+    };
+    let mut tags: Vec<String> = document.tags.iter().map(|id| name(PaperlessDefaultFieldType::Tag, *id)).collect();
+    tags.extend(update.tags.iter().flatten().map(|tag| tag.name.clone()));
+
+    for profile in context.profiles {
+        let matches = profile.matches(document_type.as_deref(), &tags) || match &profile.query {
+            Some(query) => {
+                let _permit = context.limits.paperless().await;
+                document_matches_query(context.client, context.base_url, document.id, query).await?
             }
-            '}' | ']' => {
-                brace_count -= 1;
-                if brace_count == 0 {
-                    json_end = Some(i);
-                    break; // Found the complete JSON object
-                }
+            None => false,
+        };
+        if matches {
+            slog_scope::info!("Document {} uses prompt profile {}", document.id, profile.name);
+            return Ok(Some(profile));
+        }
+    }
+    Ok(None)
+}
+
+/// Extracts the custom fields. Documents that do not fit into the context of
+/// the model are split, the values found in every part are merged and the
+/// model chooses between conflicting values. The raw answer is a list of
+/// the answers for split documents, the repairs are those of every answer.
+async fn analyze_custom_fields(
+    context: &ProcessingContext<'_>,
+    fields: &[Field],
+    document: &Document,
+    profile: Option<&PromptProfile>,
+    language: Option<whatlang::Lang>,
+) -> Result<(HashMap<String, Option<Value>>, Value, Vec<Repair>), Box<dyn std::error::Error>> {
+    let llm = context.llm();
+    let fields: Vec<Field> = fields
+        .iter()
+        .filter(|field| profile.is_none_or(|profile| profile.includes(&field.name)) && context.language_field.as_ref() != Some(&field.name))
+        .cloned()
+        .collect();
+    let translation = context.translations.iter().find(|(code, _)| *code == prompt_language(language)).map(|(_, translation)| translation);
+    let template = profile.and_then(|profile| profile.prompt.as_ref()).or(translation).unwrap_or(&context.prompt);
+    let instructions = profile.and_then(|profile| profile.instructions.as_deref());
+    let messages = |content: &str| {
+        let mut prompt = render_prompt(context, template, &fields, document, content);
+        if let Some(instructions) = instructions {
+            prompt = format!("{}\n\n{}", prompt, instructions);
+        }
+        // Templates without {{content}} get the document as separate message
+        match template.uses("content") {
+            true => vec![ChatMessage::new(ChatRole::User, prompt)],
+            false => vec![ChatMessage::new(ChatRole::System, prompt), ChatMessage::new(ChatRole::User, content.to_string())],
+        }
+    };
+    let schema = custom_fields_schema(&fields, context.mode, context.marker.field_id());
+
+    let prompt: String = messages("").iter().map(|message| message.content.as_str()).collect();
+    slog_scope::debug!("with Prompt: {}", prompt);
+    let chunks = context.chunking.chunks(&document.content, &prompt);
+    if let [content] = chunks.as_slice() {
+        let (json, response, repairs) = extract_json(&llm, messages(content), &schema, document.id).await?;
+        return Ok((json, Value::String(response), repairs));
+    }
+
+    slog_scope::info!("Document {} is too long for the model, analyzing it in {} parts", document.id, chunks.len());
+    let answers = futures::future::try_join_all(chunks.iter().map(|content| extract_json(&llm, messages(content), &schema, document.id))).await?;
+    let (mut json, conflicts) = merge_candidates(answers.iter().map(|(json, _, _)| json));
+    let mut repairs: Vec<Repair> = Vec::new();
+    let mut responses: Vec<Value> = Vec::new();
+    for (_, response, answer_repairs) in answers {
+        responses.push(Value::String(response));
+        add_repairs(&mut repairs, answer_repairs);
+    }
+    if !conflicts.is_empty() {
+        let reduce_fields: Vec<Field> = fields.iter().filter(|field| conflicts.contains_key(&field.name)).cloned().collect();
+        let reduce_schema = custom_fields_schema(&reduce_fields, context.mode, context.marker.field_id());
+        let reduce = vec![ChatMessage::new(ChatRole::User, reduce_prompt(&conflicts))];
+        let (chosen, response, reduce_repairs) = extract_json(&llm, reduce, &reduce_schema, document.id).await?;
+        for key in conflicts.keys() {
+            if let Some(value) = chosen.get(key) {
+                json.insert(key.clone(), value.clone());
             }
-            _ => {}
+        }
+        responses.push(Value::String(response));
+        add_repairs(&mut repairs, reduce_repairs);
+    }
+    Ok((json, Value::Array(responses), repairs))
+}
+
+async fn extract_json(
+    llm: &LimitedLlm<'_>,
+    messages: Vec<ChatMessage>,
+    schema: &Value,
+    document_id: u32,
+) -> Result<(HashMap<String, Option<Value>>, String, Vec<Repair>), Box<dyn std::error::Error>> {
+    let res = chat_response(llm, messages, Some(schema)).await.map_err(|e| e as Box<dyn std::error::Error>)?;
+    // Log the response from the generate_response call
+    slog_scope::debug!("LLM Response: {}", res.response);
+
+    let (json, repairs) = parse_llm_json(llm, &res.response).map_err(ResponseError::Other)?;
+    log_repairs(document_id, &repairs);
+    Ok((json, res.response, repairs))
+}
+
+fn render_prompt(context: &ProcessingContext<'_>, template: &PromptTemplate, fields: &[Field], document: &Document, content: &str) -> String {
+    let mut values = HashMap::from([
+        ("content", content.to_string()),
+        ("title", document.title.clone()),
+        ("original_file_name", document.original_file_name.clone().unwrap_or_default()),
+        ("created", document.created_date.clone().unwrap_or_else(|| document.created.clone())),
+        ("custom_fields", describe_fields(fields, context.marker.field_id(), &context.field_descriptions)),
+    ]);
+    if let Some(names) = &context.prompt_names {
+        let lists = [
+            ("existing_tags", PaperlessDefaultFieldType::Tag),
+            ("document_types", PaperlessDefaultFieldType::DocumentType),
+            ("correspondents", PaperlessDefaultFieldType::Correspondent),
+        ];
+        for (name, field_type) in lists {
+            values.insert(name, names.names(field_type).join(", "));
         }
     }
+    template.render(&values)
+}
 
-    if let (Some(start), Some(end)) = (json_start, json_end) {
-        slog_scope::debug!("{}", input.substring(start, end + 1));
-        Ok(input.substring(start, end + 1).to_string())
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    logger::init(); // Initializes the global logger
+    slog_scope::info!("Application started, version: {}", env!("CARGO_PKG_VERSION"));
+    let config = match Config::load(&cli.options) {
+        Ok(config) => config,
+        Err(e) => {
+            eprint!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let client = init_paperless_client(&config.token);
+    let base_url = config.base_url.as_str();
+
+    let llm = init_llm_backend(&config.llm, config.chunking.context_size);
+
+    let mut filter = config.filter.clone();
+    let (dry_run, reprocess, document_ids) = match cli.command.unwrap_or(Command::Run) {
+        Command::Run => (config.dry_run, Reprocess::Never, Vec::new()),
+        Command::Process { document_ids } => (config.dry_run, Reprocess::Always, document_ids),
+        Command::DryRun { document_ids } if document_ids.is_empty() => (true, Reprocess::Never, document_ids),
+        Command::DryRun { document_ids } => (true, Reprocess::Always, document_ids),
+        Command::ReprocessStale { query, limit } => {
+            filter = query.unwrap_or_else(|| config.reprocess_filter.clone());
+            (config.dry_run, Reprocess::Stale { limit: limit.or(config.reprocess_max_documents) }, Vec::new())
+        }
+        Command::ListFields => return commands::list_fields(&client, base_url).await,
+        Command::CheckConfig => return commands::check_config(&client, llm.as_ref(), &config).await,
+        Command::ResetMarker { document_ids } => return commands::reset_marker(&client, &config, &document_ids).await,
+        Command::Daemon => return daemon::run(&client, llm.as_ref(), &config, Shutdown::listen()).await,
+        Command::Serve => return webhook::run(&client, llm.as_ref(), &config, Shutdown::listen()).await,
+        Command::History { document_ids } => return commands::history(&config, &document_ids),
+        Command::Review { action } => {
+            return match action {
+                ReviewAction::List => review::list(&config),
+                ReviewAction::Show { proposal_id } => review::show(&client, &config, proposal_id).await,
+                ReviewAction::Edit { proposal_id } => review::edit(&config, proposal_id),
+                ReviewAction::Approve { proposal_ids } => review::approve(&client, &config, &proposal_ids).await,
+                ReviewAction::Reject { proposal_ids } => review::reject(&client, &config, &proposal_ids).await,
+            }
+        }
+        Command::Rollback { run, document_ids, since, until } => {
+            let selection = Selection { run_id: run, document_ids, since, until };
+            return commands::rollback(&client, &config, selection, config.dry_run).await;
+        }
+        Command::PostConsume { document_id } => {
+            let code = post_consume::run(&client, llm.as_ref(), &config, document_id).await;
+            logger::flush();
+            std::process::exit(code)
+        }
+    };
+    if dry_run {
+        slog_scope::info!("Dry run, no changes are written to paperless");
+    }
+
+    let mut fields = query_custom_fields(&client, base_url).await?;
+    let context = ProcessingContext::new(&client, llm.as_ref(), &config, &mut fields, dry_run, reprocess, Shutdown::listen()).await?;
+    if document_ids.is_empty() {
+        process_documents(&context, &mut fields, &filter).await
     } else {
-        let error_msg = "No JSON object found in the response!".to_string();
-        slog_scope::debug!("{}", error_msg);
-        Err(error_msg)
+        process_document_ids(&context, &mut fields, &document_ids).await
     }
 }
 
-fn create_mode_from_env(env_key: &str) -> Mode {
-    let mode_env = env::var(env_key).unwrap_or_else(|_| "1".to_string());
-    let mode_int = mode_env.parse::<i32>().unwrap_or(1);
-    Mode::from_int(mode_int)
+/// Parses the JSON answer of the LLM. Backends with structured output
+/// already answer with plain JSON, for all others it is searched in the text
+/// and repaired if necessary.
+fn parse_llm_json<T: DeserializeOwned>(llm: &dyn LlmBackend, response: &str) -> Result<(T, Vec<Repair>), String> {
+    if llm.model_info().structured_output {
+        if let Ok(value) = serde_json::from_str(response.trim()) {
+            return Ok((value, Vec::new()));
+        }
+    }
+    parse_json(response)
 }
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_extract_json_object() {
-        let json_str = "Some text before JSON object {\"key\": \"value\"} Some text after";
-        assert_eq!(
-            extract_json_object(json_str).unwrap(),
-            "{\"key\": \"value\"}"
-        );
-
-        let json_array_str = "Some text before JSON array [1,2,3] Some text after";
-        assert_eq!(
-            extract_json_object(json_array_str).unwrap(),
-            "[1,2,3]"
-        );
-
-        let empty_json_str = "No JSON object or array here";
-        assert!(extract_json_object(empty_json_str).is_err());
+
+fn add_repairs(repairs: &mut Vec<Repair>, more: Vec<Repair>) {
+    for repair in more {
+        if !repairs.contains(&repair) {
+            repairs.push(repair);
+        }
+    }
+}
+
+fn log_repairs(document_id: u32, repairs: &[Repair]) {
+    if !repairs.is_empty() {
+        let repairs = repairs.iter().map(|r| r.to_string()).collect::<Vec<String>>().join(", ");
+        slog_scope::warn!("Repaired LLM JSON for document {}: {}", document_id, repairs);
     }
 }
@@ -0,0 +1,218 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use reqwest::Client;
+use crate::{Document, Field};
+use crate::error::ResponseError;
+use crate::paperless::{create_custom_field, find_or_create_tag, get_default_fields, patch_document, remove_tag_from_document, CreateField, PaperlessDefaultFieldType};
+
+/// How doclytics remembers which documents it already processed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MarkerStrategy {
+    /// A boolean custom field that is set to `true`
+    CustomField(String),
+    /// A tag added to the document
+    Tag(String),
+    /// A local file with the ids of processed documents
+    Local(PathBuf),
+}
+
+impl MarkerStrategy {
+    /// Parses `field:<name>`, `tag:<name>` or `local:<path>`.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.split_once(':') {
+            Some(("field", name)) if !name.is_empty() => Ok(MarkerStrategy::CustomField(name.to_string())),
+            Some(("tag", name)) if !name.is_empty() => Ok(MarkerStrategy::Tag(name.to_string())),
+            Some(("local", path)) if !path.is_empty() => Ok(MarkerStrategy::Local(PathBuf::from(path))),
+            _ => Err(format!("Invalid marker '{}', expected field:<name>, tag:<name> or local:<path>", value)),
+        }
+    }
+
+    /// Paperless query selecting the documents that are not marked yet.
+    /// The local store can not be queried, so every document is fetched.
+    pub fn default_filter(&self) -> String {
+        match self {
+            MarkerStrategy::CustomField(name) => format!("NOT {}=true", name),
+            MarkerStrategy::Tag(name) => format!("NOT tag:\"{}\"", name),
+            MarkerStrategy::Local(_) => String::new(),
+        }
+    }
+}
+
+pub enum Marker {
+    CustomField { field_id: u32 },
+    Tag { tag_id: u32 },
+    Local(LocalStore),
+}
+
+pub struct LocalStore {
+    path: PathBuf,
+    ids: Mutex<HashSet<u32>>,
+}
+
+impl LocalStore {
+    pub fn open(path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+        let ids = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(LocalStore { path, ids: Mutex::new(ids) })
+    }
+
+    fn insert(&self, document_id: u32) -> Result<(), Box<dyn std::error::Error>> {
+        let mut ids = self.ids.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        ids.insert(document_id);
+        let mut sorted: Vec<&u32> = ids.iter().collect();
+        sorted.sort();
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string(&sorted)?)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, document_id: u32) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut ids = self.ids.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        if !ids.remove(&document_id) {
+            return Ok(false);
+        }
+        let mut sorted: Vec<&u32> = ids.iter().collect();
+        sorted.sort();
+        fs::write(&self.path, serde_json::to_string(&sorted)?)?;
+        Ok(true)
+    }
+
+    fn contains(&self, document_id: u32) -> bool {
+        self.ids.lock().map(|ids| ids.contains(&document_id)).unwrap_or(false)
+    }
+}
+
+impl Marker {
+    /// Resolves the marker, creating the custom field or tag in paperless
+    /// if it does not exist yet. In a dry run nothing is created and a
+    /// missing marker matches no document.
+    pub async fn bootstrap(
+        client: &Client,
+        base_url: &str,
+        strategy: &MarkerStrategy,
+        fields: &mut Vec<Field>,
+        dry_run: bool,
+    ) -> Result<Marker, Box<dyn std::error::Error>> {
+        match strategy {
+            MarkerStrategy::CustomField(name) => {
+                if let Some(field) = fields.iter().find(|f| f.name == *name) {
+                    return Ok(Marker::CustomField { field_id: field.id });
+                }
+                if dry_run {
+                    slog_scope::info!("Dry run: would create marker custom field: {}", name);
+                    return Ok(Marker::CustomField { field_id: 0 });
+                }
+                slog_scope::info!("Creating marker custom field: {}", name);
+                let create_field = CreateField {
+                    name: name.clone(),
+                    data_type: "boolean".to_string(),
+                    default_value: None,
+                };
+                let field = create_custom_field(client, &create_field, base_url).await?;
+                let field_id = field.id;
+                fields.push(field);
+                Ok(Marker::CustomField { field_id })
+            }
+            MarkerStrategy::Tag(name) if dry_run => {
+                let tags = get_default_fields(client, base_url, PaperlessDefaultFieldType::Tag).await?;
+                let tag_id = tags.iter().find(|tag| tag.name == *name).and_then(|tag| tag.id).unwrap_or_else(|| {
+                    slog_scope::info!("Dry run: would create marker tag: {}", name);
+                    0
+                });
+                Ok(Marker::Tag { tag_id })
+            }
+            MarkerStrategy::Tag(name) => {
+                let tag_id = find_or_create_tag(client, base_url, name).await?;
+                Ok(Marker::Tag { tag_id })
+            }
+            MarkerStrategy::Local(path) => Ok(Marker::Local(LocalStore::open(path.clone())?)),
+        }
+    }
+
+    /// Id of the custom field used as marker, it is set together with the
+    /// extracted custom fields.
+    pub fn field_id(&self) -> Option<u32> {
+        match self {
+            Marker::CustomField { field_id } => Some(*field_id),
+            _ => None,
+        }
+    }
+
+    pub fn is_marked(&self, document: &Document) -> bool {
+        match self {
+            Marker::CustomField { field_id } => document
+                .custom_fields
+                .iter()
+                .any(|f| f.field == *field_id && f.value == Some(serde_json::json!(true))),
+            Marker::Tag { tag_id } => document.tags.contains(tag_id),
+            Marker::Local(store) => store.contains(document.id),
+        }
+    }
+
+    /// Id of the tag used as marker, it is added with the document update.
+    pub fn tag_id(&self) -> Option<u32> {
+        match self {
+            Marker::Tag { tag_id } => Some(*tag_id),
+            _ => None,
+        }
+    }
+
+    /// Marks the document as processed after its update was applied. Field
+    /// and tag markers are already part of the update itself.
+    pub fn mark(&self, document_id: u32) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Marker::CustomField { .. } | Marker::Tag { .. } => Ok(()),
+            Marker::Local(store) => store.insert(document_id),
+        }
+    }
+
+    /// Removes the marker so the document is processed again on the next
+    /// run. Returns `false` if the document was not marked.
+    pub async fn reset(&self, client: &Client, base_url: &str, document: &Document) -> Result<bool, Box<dyn std::error::Error>> {
+        if !self.is_marked(document) {
+            return Ok(false);
+        }
+        match self {
+            Marker::CustomField { field_id } => {
+                let custom_fields: Vec<serde_json::Value> = document
+                    .custom_fields
+                    .iter()
+                    .map(|f| match f.field == *field_id {
+                        true => serde_json::json!({ "field": f.field, "value": false }),
+                        false => serde_json::json!({ "field": f.field, "value": f.value }),
+                    })
+                    .collect();
+                let mut payload = serde_json::Map::new();
+                payload.insert("custom_fields".to_string(), serde_json::json!(custom_fields));
+                patch_document(client, base_url, document.id, &payload).await?;
+                Ok(true)
+            }
+            Marker::Tag { tag_id } => {
+                remove_tag_from_document(client, base_url, document.id, *tag_id).await?;
+                Ok(true)
+            }
+            Marker::Local(store) => store.remove(document.id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_marker_strategy() {
+        assert_eq!(MarkerStrategy::parse("field:tagged").unwrap(), MarkerStrategy::CustomField("tagged".to_string()));
+        assert_eq!(MarkerStrategy::parse("tag:doclytics:done").unwrap(), MarkerStrategy::Tag("doclytics:done".to_string()));
+        assert_eq!(MarkerStrategy::parse("local:/app/data/processed.json").unwrap(), MarkerStrategy::Local(PathBuf::from("/app/data/processed.json")));
+        assert!(MarkerStrategy::parse("tagged").is_err());
+        assert!(MarkerStrategy::parse("tag:").is_err());
+    }
+}
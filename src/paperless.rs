@@ -1,12 +1,10 @@
-use std::collections::HashMap;
-use std::fmt;
-use std::fmt::Debug;
 use reqwest::Client;
-use serde::de::{DeserializeOwned, StdError};
+use serde::de::StdError;
 use serde_json::{Map, Value};
-use crate::{CustomField, Document, Field, Mode, Response};
+use crate::{Document, Field, Mode, Response};
 use serde::{Deserialize, Serialize};
-use crate::util::normalize_string;
+use serde::de::DeserializeOwned;
+use crate::error::ResponseError;
 
 #[derive(Clone, Copy)]
 pub enum PaperlessDefaultFieldType {
@@ -25,12 +23,42 @@ impl PaperlessDefaultFieldType {
     }
 }
 
+/// How the values proposed by the LLM are combined with the values the
+/// document already has.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergeStrategy {
+    /// Keep the existing tags and add the new ones. Single value fields
+    /// behave like `IfEmpty`.
+    Union,
+    /// Only set the field if the document has no value yet
+    IfEmpty,
+    /// Replace the existing value
+    Overwrite,
+}
+
+impl MergeStrategy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_ref() {
+            "union" => Some(MergeStrategy::Union),
+            "if_empty" => Some(MergeStrategy::IfEmpty),
+            "overwrite" => Some(MergeStrategy::Overwrite),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DefaultFieldOptions {
+    pub mode: Mode,
+    pub merge: MergeStrategy,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DefaultField {
     #[serde(skip_serializing_if = "Option::is_none")] // Skip `id` if it's None
-    id: Option<u32>,
+    pub id: Option<u32>,
     slug: String,
-    name: String,
+    pub name: String,
     matching_algorithm: u8,
 }
 
@@ -41,9 +69,12 @@ pub async fn get_data_from_paperless(
 ) -> Result<Response<Document>, Box<dyn StdError + Send + Sync>> {
     // Read token from environment
     //Define filter string
-    let filter = filter;
     slog_scope::info!("Retrieve Documents from paperless at: {}, with query: {}",url, filter);
-    let response = client.get(format!("{}/api/documents/?query={}", url, filter)).send().await?;
+    let response = if filter.is_empty() {
+        client.get(format!("{}/api/documents/", url)).send().await?
+    } else {
+        client.get(format!("{}/api/documents/?query={}", url, filter)).send().await?
+    };
 
 
     let response_result = response.error_for_status();
@@ -77,7 +108,7 @@ pub async fn get_next_data_from_paperless(client: &Client,
     // Read token from environment
     //Define filter string
     slog_scope::info!("Retrieve next page {}", url);
-    let response = client.get(format!("{}", url)).send().await?;
+    let response = client.get(url).send().await?;
 
 
     let response_result = response.error_for_status();
@@ -105,6 +136,31 @@ pub async fn get_next_data_from_paperless(client: &Client,
     }
 }
 
+/// Fetches a single document by id.
+pub async fn get_document(client: &Client, base_url: &str, document_id: u32) -> Result<Document, Box<dyn std::error::Error>> {
+    let url = format!("{}/api/documents/{}/", base_url, document_id);
+    slog_scope::info!("Retrieve document {}", document_id);
+    match client.get(&url).send().await?.error_for_status() {
+        Ok(data) => {
+            let body = data.text().await?;
+            slog_scope::trace!("Response from server while fetching document: {}", body);
+            Ok(serde_json::from_str(&body)?)
+        }
+        Err(e) => {
+            slog_scope::error!("Error while fetching document {} from paperless: {}", document_id, e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Whether the full text query finds the document.
+pub async fn document_matches_query(client: &Client, base_url: &str, document_id: u32, query: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let url = format!("{}/api/documents/", base_url);
+    let query = format!("id:{} AND ({})", document_id, query);
+    let response = client.get(&url).query(&[("query", query.as_str()), ("page_size", "1")]).send().await?.error_for_status()?;
+    let body: Value = response.json().await?;
+    Ok(body["count"].as_u64().unwrap_or(0) > 0)
+}
 
 pub fn parse_document_response(json: &str) -> Result<Response<Document>, Box<dyn StdError + Send + Sync>> {
     let data: Result<Response<Document>, _> = serde_json::from_str(json);
@@ -130,41 +186,9 @@ pub async fn query_custom_fields(
     base_url: &str,
 ) -> Result<Vec<Field>, Box<dyn std::error::Error>> {
     slog_scope::info!("Fetching custom fields from paperless at {}", base_url);
-    let res = client
-        .get(format!("{}/api/custom_fields/", base_url))
-        .send()
-        .await?;
-
-    let response_result = res.error_for_status();
-    match response_result {
-        Ok(data) => {
-            let body = data.text().await?;
-            slog_scope::debug!("Response from server while fetching documents: {}", body);
-
-            // Remove the "Document content: " prefix
-            let json = body.trim_start_matches("Field: ");
-            let data: Result<Response<Field>, _> = serde_json::from_str(json);
-            match data {
-                Ok(data) => {
-                    slog_scope::info!("Fields: {:?}", data.results);
-                    Ok(data.results)
-                }
-                Err(e) => {
-                    let column = e.column();
-                    let start = (column as isize - 30).max(0) as usize;
-                    let end = (column + 30).min(json.len());
-                    slog_scope::error!("Error occured parsing custom fields: {}", e);
-                    slog_scope::error!("Error at column {}: {}", column, &json[start..end]);
-                    slog_scope::debug!("Error occured in json {}", &json);
-                    Err(e.into())
-                }
-            }
-        }
-        Err(e) => {
-            slog_scope::error!("Error retrieving custom fields: {}", e);
-            Err(e.into())
-        }
-    }
+    let fields: Vec<Field> = get_all_results(client, format!("{}/api/custom_fields/", base_url), "custom fields").await?;
+    slog_scope::info!("Fields: {:?}", fields);
+    Ok(fields)
 }
 
 pub async fn get_default_fields(
@@ -173,217 +197,83 @@ pub async fn get_default_fields(
     endpoint: PaperlessDefaultFieldType,
 ) -> Result<Vec<DefaultField>, Box<dyn std::error::Error>>
 {
-    slog_scope::info!("Fetching custom fields from paperless at {}", base_url);
-    let res = client
-        .get(format!("{}/api/{}/", base_url, endpoint.to_string()))
-        .send()
-        .await?;
+    slog_scope::info!("Fetching {} from paperless at {}", endpoint.to_string(), base_url);
+    let objects: Vec<DefaultField> = get_all_results(client, format!("{}/api/{}/", base_url, endpoint.to_string()), endpoint.to_string()).await?;
+    slog_scope::info!("{}: {:?}", endpoint.to_string(), objects);
+    Ok(objects)
+}
 
-    let response_result = res.error_for_status();
-    match response_result {
-        Ok(data) => {
-            let body = data.text().await?;
-            slog_scope::debug!("Response from server while fetching documents: {}", body);
-
-            // Remove the "Field: " prefix if necessary
-            let json = body.trim_start_matches("Field: ");
-            let data: Result<Response<DefaultField>, _> = serde_json::from_str(json);
-            match data {
-                Ok(data) => {
-                    slog_scope::info!("{}: {:?}", endpoint.to_string(), data.results);
-                    Ok(data.results)
-                }
-                Err(e) => {
-                    let column = e.column();
-                    let start = (column as isize - 30).max(0) as usize;
-                    let end = (column + 30).min(json.len());
-                    slog_scope::error!("Error occurred parsing custom fields: {}", e);
-                    slog_scope::error!("Error at column {}: {}", column, &json[start..end]);
-                    slog_scope::debug!("Error occurred in json {}", &json);
-                    Err(e.into())
-                }
+/// Collects the results of every page of a list endpoint by following `next`.
+async fn get_all_results<T: DeserializeOwned>(
+    client: &Client,
+    url: String,
+    name: &str,
+) -> Result<Vec<T>, Box<dyn std::error::Error>> {
+    let mut results = Vec::new();
+    let mut next = Some(url);
+    while let Some(url) = next {
+        let data = match client.get(&url).send().await?.error_for_status() {
+            Ok(data) => data,
+            Err(e) => {
+                slog_scope::error!("Error retrieving {}: {}", name, e);
+                return Err(e.into());
+            }
+        };
+        let body = data.text().await?;
+        slog_scope::debug!("Response from server while fetching {}: {}", name, body);
+
+        // Remove the "Field: " prefix if necessary
+        let json = body.trim_start_matches("Field: ");
+        match serde_json::from_str::<Response<T>>(json) {
+            Ok(data) => {
+                results.extend(data.results);
+                next = data.next;
+            }
+            Err(e) => {
+                let column = e.column();
+                let start = (column as isize - 30).max(0) as usize;
+                let end = (column + 30).min(json.len());
+                slog_scope::error!("Error occurred parsing {}: {}", name, e);
+                slog_scope::error!("Error at column {}: {}", column, &json[start..end]);
+                slog_scope::debug!("Error occurred in json {}", &json);
+                return Err(e.into());
             }
-        }
-        Err(e) => {
-            slog_scope::error!("Error retrieving custom fields: {}", e);
-            Err(e.into())
         }
     }
+    Ok(results)
 }
-pub async fn update_document_fields(
+/// Sends all changes for a document in a single request.
+pub async fn patch_document(
     client: &Client,
-    document_id: u32,
-    fields: &Vec<Field>,
-    metadata: &HashMap<String, Option<Value>>,
     base_url: &str,
-    mode: Mode,
+    document_id: u32,
+    payload: &Map<String, Value>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut custom_fields = Vec::new();
-
-    // Use `if let` to conditionally execute code if the 'tagged' field is found.
-    let field = match fields.iter().find(|&f| f.name == "tagged") {
-        Some(field) => field,
-        None => {
-            slog_scope::error!("{} field not found in the provided fields.", "'tagged'");
-            return Err(Box::new(fmt::Error::default())); // Use a standard library error type like fmt::Error.
-        }
-    };
-
-    let tagged_field = CustomField {
-        field: field.id,
-        value: Some(serde_json::json!(true)),
-    };
-
-    // Add this tagged_field to your custom_fields collection or use it as needed.
-    custom_fields.push(tagged_field);
-
-    for (key, value) in metadata {
-        if key == "title" {
-            continue;
-        }
-
-        if let Some(field) = fields.iter().find(|&f| f.name == *key) {
-            let custom_field = convert_field_to_custom_field(value, field);
-            custom_fields.push(custom_field);
-        } else {
-            if matches!(mode, Mode::Create) {
-                slog_scope::info!("Creating field: {}", key);
-                let create_field = CreateField {
-                    name: key.clone(),
-                    data_type: "Text".to_string(),
-                    default_value: None,
-                };
-                match create_custom_field(client, &create_field, base_url).await
-                {
-                    Ok(new_field) => {
-                        let custom_field = convert_field_to_custom_field(value, &new_field);
-                        custom_fields.push(custom_field)
-                    }
-                    Err(e) => {
-                        slog_scope::error!("Error: {} creating custom field: {}, skipping...",e, key)
-                    }
-                }
-            }
-        }
-    }
-    // Check if tagged_field_id has a value and then proceed.
-
-    let mut payload = serde_json::Map::new();
-
-    payload.insert("custom_fields".to_string(), serde_json::json!(custom_fields));
-    if let Some(value) = metadata.get("title").and_then(|v| v.as_ref().and_then(|v| v.as_str())) {
-        payload.insert("title".to_string(), serde_json::json!(value));
-    }
-    if payload.is_empty() {
-        slog_scope::warn!("{}", "payload is empty, not updating fields");
-        return Err(Box::new(fmt::Error::default())); // Use a standard library error type like fmt::Error.
-    }
     let url = format!("{}/api/documents/{}/", base_url, document_id);
     slog_scope::info!("Updating document with ID: {}", document_id);
-    slog_scope::debug!("Request Payload: {}", map_to_string(&payload));
+    slog_scope::debug!("Request Payload: {}", map_to_string(payload));
 
-    for (key, value) in &payload {
-        slog_scope::debug!("{}: {}", key, value);
-    }
-    let res = client.patch(&url).json(&payload).send().await?;
-    let response_result = res.error_for_status();
-    match response_result {
+    let res = client.patch(&url).json(payload).send().await?;
+    match res.error_for_status() {
         Ok(data) => {
-            let body = data.text().await?;
-            slog_scope::trace!("{}", body);
+            if let Ok(body) = data.text().await {
+                slog_scope::trace!("{}", body);
+            }
             slog_scope::info!("Document with ID: {} successfully updated", document_id);
             Ok(())
         }
         Err(e) => {
-            slog_scope::error!("Error while updating document fields: {}", e);
+            slog_scope::error!("Error while updating document: {}", e);
             Err(e.into())
         }
     }
 }
 
-/// This function update the default fields like tags, correspondents and document_types in paperless
-/// it is checked if a field exists on the server and if not, it is created
-/// 
-pub async fn update_document_default_fields(
-    client: &Client,
-    document_id: u32,
-    fields: &Vec<DefaultField>,
-    data: Vec<String>,
-    base_url: &str,
-    endpoint: PaperlessDefaultFieldType,
-    mode: Mode,
-) -> Option<Box<dyn std::error::Error>> {
-    let mut default_field_ids = Vec::new();
-
-    for value in data {
-
-        if let Some(field) = fields.iter().find(|&f| normalize_string(&*f.name) == normalize_string(&*value)) {
-            let default_field_id = field.id;
-            default_field_ids.push(default_field_id);
-        } else {
-            if matches!(mode, Mode::Create) {
-                slog_scope::info!("Creating {}: {}", endpoint.to_string(), value);
-                let create_field = DefaultField {
-                    id: None,
-                    name: value.clone(),
-                    slug: value.clone(),
-                    matching_algorithm: 6,
-                };
-                match create_default_field(client, &create_field, base_url, endpoint).await
-                {
-                    Ok(new_field) => {
-                        default_field_ids.push(new_field.id)
-                    }
-                    Err(e) => {
-                        slog_scope::error!("Error: {} creating custom field: {}, skipping...",e, value)
-                    }
-                }
-            }
-        }
-    }
-
-    let mut payload = serde_json::Map::new();
-    payload.insert(endpoint.to_string().to_string(), serde_json::json!(default_field_ids));
-
-    if payload.is_empty() {
-        slog_scope::warn!("{}", "payload is empty, not updating fields");
-        return None
-    }
-    let url = format!("{}/api/documents/{}/", base_url, document_id);
-    slog_scope::info!("Updating document with ID: {}", document_id);
-    slog_scope::debug!("Request Payload: {}", map_to_string(&payload));
-
-    for (key, value) in &payload {
-        slog_scope::debug!("{}: {}", key, value);
-    }
-    let res = client.patch(&url).json(&payload).send().await;
-    let response_result = res;
-    match response_result {
-        Ok(data) => {
-            let body = data.text().await;
-            slog_scope::info!("Document with ID: {} successfully updated", document_id);
-            None
-        }
-        Err(e) => {
-            slog_scope::error!("Error while updating document fields: {}", e);
-            Some(Box::new(e))
-        }
-    }
-}
-
-fn convert_field_to_custom_field(value: &Option<Value>, field: &Field) -> CustomField {
-    let custom_field = CustomField {
-        field: field.id.clone(),
-        value: value.as_ref().cloned(),
-    };
-    custom_field
-}
-
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CreateField {
-    name: String,
-    default_value: Option<String>,
-    data_type: String,
+    pub name: String,
+    pub default_value: Option<String>,
+    pub data_type: String,
 }
 
 pub async fn create_custom_field(
@@ -402,10 +292,10 @@ pub async fn create_custom_field(
         Ok(data) => {
             let body = data.text().await?;
             slog_scope::trace!("{}", body);
-            let field: Result<Response<Field>, _> = serde_json::from_str(&body);
+            let field: Result<Field, _> = serde_json::from_str(&body);
             match field {
                 Ok(field) => {
-                    Ok(field.results[0].clone()) // TODO: improve
+                    Ok(field)
                 }
                 Err(e) => {
                     slog_scope::debug!("Creating field response: {}", body);
@@ -462,3 +352,74 @@ fn map_to_string(map: &Map<String, Value>) -> String {
         .join(", ")
 }
 
+/// Creates a tag, document type or correspondent and returns its id.
+pub async fn create_named_object(
+    client: &Client,
+    base_url: &str,
+    field_type: PaperlessDefaultFieldType,
+    name: &str,
+    matching_algorithm: u8,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    slog_scope::info!("Creating {}: {}", field_type.to_string(), name);
+    let object = DefaultField {
+        id: None,
+        slug: name.to_string(),
+        name: name.to_string(),
+        matching_algorithm,
+    };
+    let object = create_default_field(client, &object, base_url, field_type).await?;
+    object.id.ok_or_else(|| ResponseError::Other(format!("paperless returned no id for {}", name)).into())
+}
+
+/// Removes a tag without touching the other tags of the document.
+pub async fn remove_tag_from_document(
+    client: &Client,
+    base_url: &str,
+    document_id: u32,
+    tag_id: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let url = format!("{}/api/documents/bulk_edit/", base_url);
+    let payload = serde_json::json!({
+        "documents": [document_id],
+        "method": "remove_tag",
+        "parameters": { "tag": tag_id },
+    });
+    match client.post(&url).json(&payload).send().await?.error_for_status() {
+        Ok(_) => {
+            slog_scope::info!("Removed tag {} from document with ID: {}", tag_id, document_id);
+            Ok(())
+        }
+        Err(e) => {
+            slog_scope::error!("Error while removing tag from document: {}", e);
+            Err(e.into())
+        }
+    }
+}
+
+/// Returns the id of the tag called `name`, creating it without automatic
+/// matching if it does not exist.
+pub async fn find_or_create_tag(
+    client: &Client,
+    base_url: &str,
+    name: &str,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    find_or_create_named_object(client, base_url, PaperlessDefaultFieldType::Tag, name, 0).await
+}
+
+/// Creates the object unless one with the same name exists, e.g. because
+/// another document suggested it first. Paperless compares names ignoring
+/// case, so the lookup does as well.
+pub async fn find_or_create_named_object(
+    client: &Client,
+    base_url: &str,
+    field_type: PaperlessDefaultFieldType,
+    name: &str,
+    matching_algorithm: u8,
+) -> Result<u32, Box<dyn std::error::Error>> {
+    let url = reqwest::Url::parse_with_params(&format!("{}/api/{}/", base_url, field_type.to_string()), &[("name__iexact", name)])?;
+    let objects: Vec<DefaultField> = get_all_results(client, url.to_string(), field_type.to_string()).await?;
+    if let Some(id) = objects.iter().find(|object| object.name.to_lowercase() == name.to_lowercase()).and_then(|object| object.id) {
+        return Ok(id);
+    }
+    create_named_object(client, base_url, field_type, name, matching_algorithm).await
+}
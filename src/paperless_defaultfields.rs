@@ -1,78 +1,89 @@
-use ollama_rs::Ollama;
-use reqwest::Client;
-use serde::de::StdError;
-use crate::{extract_json_object, Document, Field, Mode};
+use crate::{log_repairs, parse_llm_json, Document, Mode, ProcessingContext};
+use crate::document_update::{needs_default_field, ObjectRef};
 use crate::llm_api::generate_response;
-use crate::paperless::{get_default_fields, update_document_default_fields, update_document_fields, DefaultField, PaperlessDefaultFieldType};
+use crate::schema::string_array_schema;
+use crate::error::ResponseError;
+use crate::json_repair::Repair;
+use crate::paperless::{get_default_fields, DefaultField, DefaultFieldOptions, PaperlessDefaultFieldType};
+use crate::util::normalize_string;
 
- const ANSWER_INSTRUCTION: &'static str = "The result should be a only a non-nested one dimensional json array of correctly quoted strings and nothing else. The answer should start and end with the square bracket. The document is: ";
-async fn construct_document_type_prompt(client: &Client, base_url: &str) -> Result<String, Box<dyn StdError + Send + Sync>> {
-    let document_types = get_default_fields(client, base_url, PaperlessDefaultFieldType::DocumentType).await;
-    let base_prompt = format!("Determine the type of this document from the following available document types: {:?}, if none of these fit the document, create a new one. ", document_types);
-    Ok(base_prompt)
+const ANSWER_INSTRUCTION: &str = "The result should be a only a non-nested one dimensional json array of correctly quoted strings and nothing else. The answer should start and end with the square bracket. The document is: ";
+fn construct_document_type_prompt(document_types: &[String]) -> String {
+    format!("Determine the type of this document from the following available document types: {:?}, if none of these fit the document, create a new one. ", document_types)
 }
 
 
-async fn construct_tag_prompt(client: &Client, base_url: &str) -> Result<String, Box<dyn StdError + Send + Sync>> {
-    let document_types = get_default_fields(client, base_url, PaperlessDefaultFieldType::Tag).await;
-    let base_prompt = format!("Determine the type of this document from the following available document types: {:?}, if none of these fit the document, create a new one. ", document_types);
-    Ok(base_prompt)
+fn construct_tag_prompt(tags: &[String]) -> String {
+    format!("Determine suitable tags for this document from the following available tags: {:?}, if none of these fit the document, create a new one. ", tags)
 }
-async fn construct_correspondent_prompt(client: &Client, base_url: &str) -> Result<String, Box<dyn StdError + Send + Sync>> {
-    let document_types = get_default_fields(client, base_url, PaperlessDefaultFieldType::Correspondent).await;
-    let base_prompt = format!("Determine possible correspondents from this document from the following available correspondents: {:?}, if none of these fit the document, create a maximum of one new one. The result should be a only a json array of string and nothing else. The answer should start and end with the square bracket. ", document_types);
-    Ok(base_prompt)
+fn construct_correspondent_prompt(correspondents: &[String]) -> String {
+    format!("Determine possible correspondents from this document from the following available correspondents: {:?}, if none of these fit the document, create a maximum of one new one. The result should be a only a json array of string and nothing else. The answer should start and end with the square bracket. ", correspondents)
 }
 
+/// Objects the LLM suggested for the tags, document type or correspondent.
+pub struct Suggestion {
+    pub objects: Vec<ObjectRef>,
+    /// Raw answer of the LLM
+    pub response: String,
+    /// Every existing object of the type, used to name the current values
+    pub known: Vec<DefaultField>,
+    /// Repairs applied to the answer before it could be parsed
+    pub repairs: Vec<Repair>,
+}
 
-pub async fn extract_default_fields(ollama: &Ollama, model: &str, prompt_base: &String, client: &Client, fields: &Vec<DefaultField>, base_url: &str, document: &Document, mode: Mode, field_type: PaperlessDefaultFieldType) -> Option<Box<dyn StdError>> {
+/// Asks the LLM for the tags, document type or correspondent of the document.
+/// Returns `None` if the field is not analyzed or the document already has a
+/// value that is kept. Nothing is written to paperless here.
+pub async fn suggest_default_fields(
+    context: &ProcessingContext<'_>,
+    document: &Document,
+    options: DefaultFieldOptions,
+    field_type: PaperlessDefaultFieldType,
+) -> Result<Option<Suggestion>, Box<dyn std::error::Error>> {
+    if matches!(options.mode, Mode::NoAnalyze) {
+        return Ok(None);
+    }
+    if !needs_default_field(document, field_type, options.merge) {
+        slog_scope::debug!("Document {} already has a value, skipping", document.id);
+        return Ok(None);
+    }
+    let permit = context.limits.paperless().await;
+    let fields: Vec<DefaultField> = get_default_fields(context.client, context.base_url, field_type).await?;
+    drop(permit);
+    let names: Vec<String> = fields.iter().map(|f| f.name.clone()).collect();
     let prompt = match field_type {
-        PaperlessDefaultFieldType::Correspondent => construct_correspondent_prompt(client, base_url).await,
-        PaperlessDefaultFieldType::Tag => construct_tag_prompt(client, base_url).await,
-        PaperlessDefaultFieldType::DocumentType => construct_document_type_prompt(client, base_url).await,
+        PaperlessDefaultFieldType::Correspondent => construct_correspondent_prompt(&names),
+        PaperlessDefaultFieldType::Tag => construct_tag_prompt(&names),
+        PaperlessDefaultFieldType::DocumentType => construct_document_type_prompt(&names),
     };
-    match prompt {
-        Ok(prompt) => {
-            let prompt_with_document = prompt + &*ANSWER_INSTRUCTION + &*document.content;
-            match generate_response(ollama, &model.to_string(), prompt_with_document).await {
-                Ok(res) => {
-                    // Log the response from the generate_response call
-                    slog_scope::debug!("LLM Response: {}", res.response);
+    let prompt = prompt + ANSWER_INSTRUCTION;
+    // The beginning is enough to classify a long document
+    let content = context.chunking.chunks(&document.content, &prompt)[0];
+    let prompt_with_document = prompt + content;
+    let llm = context.llm();
+    let res = generate_response(&llm, prompt_with_document, Some(&string_array_schema())).await.map_err(|e| e as Box<dyn std::error::Error>)?;
+    // Log the response from the generate_response call
+    slog_scope::debug!("LLM Response: {}", res.response);
 
-                    match extract_json_object(&res.response) {
-                        Ok(json_str) => {
-                            // Log successful JSON extraction
-                            slog_scope::debug!("Extracted JSON Object: {}", json_str);
+    let (values, repairs): (Vec<String>, _) = parse_llm_json(&llm, &res.response).map_err(ResponseError::Other)?;
+    log_repairs(document.id, &repairs);
+    let objects = resolve_default_fields(&fields, values, options.mode);
+    Ok(Some(Suggestion { objects, response: res.response, known: fields, repairs }))
+}
 
-                            match serde_json::from_str(&json_str) {
-                                Ok(json) => update_document_default_fields(client, document.id, &fields, json, base_url, field_type, mode).await,
-                                Err(e) => {
-                                    slog_scope::error!("Error parsing llm response json {}", e.to_string());
-                                    slog_scope::debug!("JSON String was: {}", & json_str);
-                                    Some(Box::new(e))
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            slog_scope::error ! ("{}", e);
-                            None
-                        }
-                    }
-                }
-                Err(e) => {
-                    slog_scope::error ! ("Error generating llm response: {}", e);
-                    None
-                }
-            }
-        }
-        Err(e) => {
-            Some(e)
+/// Looks up the objects named by the LLM. Unknown names are only kept in
+/// `Mode::Create`, they are created when the update is applied.
+fn resolve_default_fields(fields: &[DefaultField], values: Vec<String>, mode: Mode) -> Vec<ObjectRef> {
+    let mut objects: Vec<ObjectRef> = Vec::new();
+    for value in values {
+        let object = match fields.iter().find(|&f| normalize_string(&f.name) == normalize_string(&value)) {
+            Some(field) => ObjectRef { id: field.id, name: field.name.clone() },
+            None if matches!(mode, Mode::Create) => ObjectRef { id: None, name: value },
+            None => continue,
+        };
+        if !objects.contains(&object) {
+            objects.push(object);
         }
     }
+    objects
 }
-pub fn determine_if_type_exists(
-    client: &Client,
-    base_url: &str,
-) {
-    //TODO:  
-}
\ No newline at end of file
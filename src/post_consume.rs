@@ -0,0 +1,76 @@
+use std::env;
+use reqwest::Client;
+use tokio::sync::Mutex;
+use crate::{process_document, Outcome, ProcessingContext, Reprocess};
+use crate::config::Config;
+use crate::llm_api::LlmBackend;
+use crate::paperless::{get_document, query_custom_fields};
+use crate::shutdown::Shutdown;
+
+/// The document was updated, reported in a dry run, queued for review or is
+/// already marked.
+pub const EXIT_OK: i32 = 0;
+/// Paperless could not be reached or the document does not exist.
+pub const EXIT_PAPERLESS: i32 = 3;
+/// The LLM could not be reached or gave no usable answer.
+pub const EXIT_ANALYSIS: i32 = 4;
+/// The changes could not be written to Paperless.
+pub const EXIT_UPDATE: i32 = 5;
+/// The marker, the review settings or the state database could not be set
+/// up.
+pub const EXIT_CONFIG: i32 = 6;
+
+/// Processes the document Paperless just consumed and returns the exit code
+/// for the post-consume script.
+pub async fn run(client: &Client, llm: &dyn LlmBackend, config: &Config, document_id: u32) -> i32 {
+    let file_name = env::var("DOCUMENT_ORIGINAL_FILENAME").or_else(|_| env::var("DOCUMENT_FILE_NAME")).unwrap_or_default();
+    slog_scope::info!("Post-consume processing of document {} {}", document_id, file_name);
+
+    let mut fields = match query_custom_fields(client, &config.base_url).await {
+        Ok(fields) => fields,
+        Err(e) => {
+            slog_scope::error!("Could not query custom fields: {}", e);
+            return EXIT_PAPERLESS;
+        }
+    };
+    let context = match ProcessingContext::new(client, llm, config, &mut fields, config.dry_run, Reprocess::Never, Shutdown::listen()).await {
+        Ok(context) => context,
+        Err(e) => {
+            slog_scope::error!("Could not prepare processing: {}", e);
+            return EXIT_CONFIG;
+        }
+    };
+    let document = match get_document(client, &config.base_url, document_id).await {
+        Ok(document) => document,
+        Err(e) => {
+            slog_scope::error!("Could not fetch document {}: {}", document_id, e);
+            return EXIT_PAPERLESS;
+        }
+    };
+    let outcome = process_document(&context, &Mutex::new(fields), &document).await;
+    if let Outcome::AnalysisFailed(e) | Outcome::UpdateFailed(e) = &outcome {
+        slog_scope::error!("Document {} not updated: {}", document_id, e);
+    }
+    exit_code(&outcome)
+}
+
+fn exit_code(outcome: &Outcome) -> i32 {
+    match outcome {
+        Outcome::Skipped | Outcome::Updated | Outcome::Reported | Outcome::Queued => EXIT_OK,
+        Outcome::AnalysisFailed(_) => EXIT_ANALYSIS,
+        Outcome::UpdateFailed(_) => EXIT_UPDATE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ResponseError;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&Outcome::Skipped), EXIT_OK);
+        assert_eq!(exit_code(&Outcome::AnalysisFailed(Box::new(ResponseError::Other("timeout".to_string())))), EXIT_ANALYSIS);
+        assert_eq!(exit_code(&Outcome::UpdateFailed(Box::new(ResponseError::Other("forbidden".to_string())))), EXIT_UPDATE);
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use serde::Deserialize;
+use crate::Field;
+use crate::util::normalize_string;
+
+/// Placeholders a prompt template may contain.
+pub const VARIABLES: [&str; 8] = [
+    "content",
+    "title",
+    "original_file_name",
+    "created",
+    "custom_fields",
+    "existing_tags",
+    "document_types",
+    "correspondents",
+];
+
+/// A prompt with `{{name}}` placeholders that are filled in for every
+/// document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptTemplate {
+    source: String,
+    /// Position and name of every placeholder, in order
+    placeholders: Vec<(Range<usize>, String)>,
+}
+
+impl PromptTemplate {
+    /// Parses the template, every placeholder has to be one of `VARIABLES`.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut placeholders = Vec::new();
+        let mut offset = 0;
+        while let Some(start) = source[offset..].find("{{").map(|i| offset + i) {
+            let end = source[start..].find("}}").map(|i| start + i + 2).ok_or_else(|| format!("unclosed placeholder at '{}'", excerpt(&source[start..])))?;
+            let name = source[start + 2..end - 2].trim();
+            if !VARIABLES.contains(&name) {
+                return Err(format!("unknown placeholder {{{{{}}}}}, expected one of {}", name, VARIABLES.join(", ")));
+            }
+            placeholders.push((start..end, name.to_string()));
+            offset = end;
+        }
+        Ok(PromptTemplate { source: source.to_string(), placeholders })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn uses(&self, name: &str) -> bool {
+        self.placeholders.iter().any(|(_, used)| used == name)
+    }
+
+    /// Replaces every placeholder by its value, missing values are left empty.
+    pub fn render(&self, values: &HashMap<&str, String>) -> String {
+        let mut prompt = String::with_capacity(self.source.len());
+        let mut offset = 0;
+        for (range, name) in &self.placeholders {
+            prompt.push_str(&self.source[offset..range.start]);
+            prompt.push_str(values.get(name.as_str()).map_or("", String::as_str));
+            offset = range.end;
+        }
+        prompt.push_str(&self.source[offset..]);
+        prompt
+    }
+}
+
+fn excerpt(text: &str) -> String {
+    text.chars().take(20).collect()
+}
+
+/// A prompt and field set used instead of the global ones for the documents
+/// it matches. A document matches if it has one of the document types or
+/// tags, or if it is found by the query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptProfile {
+    pub name: String,
+    pub document_types: Vec<String>,
+    pub tags: Vec<String>,
+    /// Paperless query, e.g. `correspondent:"Tax office"`
+    pub query: Option<String>,
+    /// Custom fields to extract, all fields if empty
+    pub fields: Vec<String>,
+    /// Replaces the global prompt
+    pub prompt: Option<PromptTemplate>,
+    /// Appended to the prompt
+    pub instructions: Option<String>,
+}
+
+/// A `[[profile]]` table of the configuration file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileSettings {
+    name: String,
+    #[serde(default)]
+    document_types: Vec<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    query: Option<String>,
+    #[serde(default)]
+    fields: Vec<String>,
+    prompt: Option<String>,
+    template_file: Option<String>,
+    instructions: Option<String>,
+}
+
+impl PromptProfile {
+    pub fn from_toml(value: toml::Value) -> Result<Self, String> {
+        let settings: ProfileSettings = value.try_into().map_err(|e: toml::de::Error| e.message().to_string())?;
+        let error = |message: &str| format!("profile {}: {}", settings.name, message);
+        if settings.document_types.is_empty() && settings.tags.is_empty() && settings.query.is_none() {
+            return Err(error("needs document_types, tags or query"));
+        }
+        let prompt = match (&settings.prompt, &settings.template_file) {
+            (Some(_), Some(_)) => return Err(error("prompt and template_file cannot both be set")),
+            (Some(prompt), None) => Some(PromptTemplate::parse(prompt).map_err(|e| error(&e))?),
+            (None, Some(path)) => {
+                let source = std::fs::read_to_string(path).map_err(|e| error(&format!("could not read {}: {}", path, e)))?;
+                Some(PromptTemplate::parse(&source).map_err(|e| error(&format!("{}: {}", path, e)))?)
+            }
+            (None, None) => None,
+        };
+        Ok(PromptProfile {
+            name: settings.name,
+            document_types: settings.document_types,
+            tags: settings.tags,
+            query: settings.query,
+            fields: settings.fields,
+            prompt,
+            instructions: settings.instructions,
+        })
+    }
+
+    /// Whether the document type or one of the tags selects this profile.
+    pub fn matches(&self, document_type: Option<&str>, tags: &[String]) -> bool {
+        let listed = |list: &[String], name: &str| list.iter().any(|listed| normalize_string(listed) == normalize_string(name));
+        document_type.is_some_and(|name| listed(&self.document_types, name)) || tags.iter().any(|name| listed(&self.tags, name))
+    }
+
+    /// Whether the custom field is extracted with this profile.
+    pub fn includes(&self, field: &str) -> bool {
+        self.fields.is_empty() || self.fields.iter().any(|name| name == field)
+    }
+}
+
+/// Built-in prompt for `language`, listing the custom fields of Paperless.
+pub fn default_prompt(language: &str) -> PromptTemplate {
+    let source = match language {
+        "DE" => "Bitte ziehe die Metadaten aus dem bereitgestellten Dokument und antworte im JSON-Format. \
+            Die Felder, welche ich brauche, sind:\n\
+            - title: ein kurzer Titel für das Dokument\n\
+            {{custom_fields}}\n\
+            Analysiere das Dokument, um die Werte für diese Felder zu finden, und forme die Antwort als JSON-Objekt \
+            mit den Feldnamen als Schlüssel. Verwende die wahrscheinlichste Antwort für jedes Feld in der gleichen \
+            Sprache wie das Dokument und null, wenn das Dokument keinen Wert enthält. Die Antwort sollte nur das \
+            JSON-Objekt enthalten, keine zusätzlichen Texte oder Erklärungen, und mit geschweiften Klammern beginnen \
+            und enden.",
+        "FR" => "Veuillez extraire les métadonnées du document fourni et répondre au format JSON. \
+            Les champs dont j'ai besoin sont :\n\
+            - title : un titre court pour le document\n\
+            {{custom_fields}}\n\
+            Analysez le document pour trouver les valeurs de ces champs et formez la réponse comme un objet JSON avec \
+            les noms des champs comme clés. Utilisez la réponse la plus probable pour chaque champ, dans la langue du \
+            document, et null si le document ne contient pas de valeur. La réponse doit contenir uniquement l'objet \
+            JSON, sans texte ni explication supplémentaire, et commencer et finir par des accolades.",
+        _ => "Please extract metadata from the provided document and return it in JSON format. \
+            The fields I need are:\n\
+            - title: a short title for the document\n\
+            {{custom_fields}}\n\
+            Analyze the document to find the values for these fields and format the response as a JSON object with \
+            the field names as keys. Use the most likely answer for each field and null if the document does not \
+            contain a value. The response should contain only the JSON object, no additional text or explanation, \
+            the answer should start and end with curly brackets.",
+    };
+    PromptTemplate::parse(source).expect("built-in prompt is valid")
+}
+
+/// One line per custom field the model should fill, with the expected
+/// format and the description configured for the field.
+pub fn describe_fields(fields: &[Field], marker_field: Option<u32>, descriptions: &HashMap<String, String>) -> String {
+    fields
+        .iter()
+        .filter(|field| Some(field.id) != marker_field)
+        .map(|field| match descriptions.get(&field.name) {
+            Some(description) => format!("- {} ({}): {}", field.name, value_format(field), description),
+            None => format!("- {} ({})", field.name, value_format(field)),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn value_format(field: &Field) -> String {
+    let extra = field.extra_data.as_ref();
+    match field.data_type.as_str() {
+        "date" => "date as YYYY-MM-DD".to_string(),
+        "boolean" => "true or false".to_string(),
+        "integer" => "whole number".to_string(),
+        "float" => "number".to_string(),
+        "monetary" => match extra.and_then(|extra| extra.default_currency.as_deref()) {
+            Some(currency) => format!("amount, e.g. {}12.50", currency),
+            None => "amount with currency code, e.g. EUR12.50".to_string(),
+        },
+        "url" => "URL".to_string(),
+        "documentlink" => "document id".to_string(),
+        "select" => {
+            let options: Vec<&str> = extra.iter().flat_map(|extra| extra.select_options.iter().map(|option| option.label())).collect();
+            format!("one of: {}", options.join(", "))
+        }
+        _ => "text".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render_template() {
+        let template = PromptTemplate::parse("Fill {{ custom_fields }} for \"{{title}}\":\n{{content}}").unwrap();
+        assert!(template.uses("content"));
+        assert!(!template.uses("existing_tags"));
+        let fields: Vec<Field> = serde_json::from_value(json!([
+            { "id": 1, "name": "total", "data_type": "monetary" },
+            { "id": 2, "name": "tagged", "data_type": "boolean" },
+            { "id": 3, "name": "urgency", "data_type": "select", "extra_data": { "select_options": ["low", "high"], "default_currency": null } },
+            { "id": 4, "name": "date_received", "data_type": "date" }
+        ])).unwrap();
+        let descriptions = HashMap::from([("date_received".to_string(), "when the letter arrived".to_string())]);
+        let values = HashMap::from([
+            ("custom_fields", describe_fields(&fields, Some(2), &descriptions)),
+            ("title", "Scan".to_string()),
+            ("content", "Invoice 42".to_string()),
+        ]);
+        assert_eq!(template.render(&values), "Fill - total (amount with currency code, e.g. EUR12.50)\n- urgency (one of: low, high)\n- date_received (date as YYYY-MM-DD): when the letter arrived for \"Scan\":\nInvoice 42");
+
+        assert_eq!(PromptTemplate::parse("{{contents}}").unwrap_err(), format!("unknown placeholder {{{{contents}}}}, expected one of {}", VARIABLES.join(", ")));
+        assert!(PromptTemplate::parse("{{content").is_err());
+        assert!(PromptTemplate::parse("No placeholders").unwrap().placeholders.is_empty());
+        assert!(default_prompt("DE").uses("custom_fields"));
+        assert!(default_prompt("FR").uses("custom_fields"));
+    }
+
+    #[test]
+    fn test_profile() {
+        let profile = PromptProfile::from_toml(toml::toml! {
+            name = "invoice"
+            document_types = ["Invoice"]
+            tags = ["Bill"]
+            fields = ["total"]
+            prompt = "Extract the total: {{custom_fields}}"
+        }.into()).unwrap();
+        assert!(profile.matches(Some("invoice"), &[]));
+        assert!(profile.matches(None, &["Tax".to_string(), "bill".to_string()]));
+        assert!(!profile.matches(Some("Payslip"), &["Tax".to_string()]));
+        assert!(profile.includes("total"));
+        assert!(!profile.includes("sender"));
+
+        let error = PromptProfile::from_toml(toml::toml! { name = "letter" fields = ["sender"] }.into()).unwrap_err();
+        assert_eq!(error, "profile letter: needs document_types, tags or query");
+        assert!(PromptProfile::from_toml(toml::toml! { name = "letter" tags = ["Letter"] instruction = "typo" }.into()).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use std::{env, fs};
+use reqwest::Client;
+use serde_json::Value;
+use tokio::sync::Mutex;
+use crate::Document;
+use crate::commands::open_state;
+use crate::config::Config;
+use crate::document_update::DocumentUpdate;
+use crate::dry_run::{self, ObjectNames};
+use crate::error::ResponseError;
+use crate::marker::Marker;
+use crate::paperless::{get_document, query_custom_fields, PaperlessDefaultFieldType};
+use crate::state::{Proposal, ProposalStatus, StateStore, Status};
+use crate::util::normalize_string;
+
+/// Which documents need approval before their changes are applied.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ReviewRules {
+    /// Every document needs approval
+    pub all: bool,
+    /// Documents that have or would get one of these tags
+    pub tags: Vec<String>,
+    /// Documents that have or would get one of these document types
+    pub document_types: Vec<String>,
+}
+
+impl ReviewRules {
+    pub fn is_enabled(&self) -> bool {
+        self.all || !self.tags.is_empty() || !self.document_types.is_empty()
+    }
+
+    fn requires_review(&self, update: &DocumentUpdate, document: &Document, names: &ObjectNames) -> bool {
+        if self.all {
+            return true;
+        }
+        let listed = |list: &[String], name: &str| list.iter().any(|listed| normalize_string(listed) == normalize_string(name));
+        let current_tags = document.tags.iter().map(|id| names.name(PaperlessDefaultFieldType::Tag, *id));
+        let proposed_tags = update.tags.iter().flatten().map(|tag| tag.name.clone());
+        if current_tags.chain(proposed_tags).any(|name| listed(&self.tags, &name)) {
+            return true;
+        }
+        let current_type = document.document_type.map(|id| names.name(PaperlessDefaultFieldType::DocumentType, id));
+        let proposed_type = update.document_type.as_ref().map(|object| object.name.clone());
+        current_type.into_iter().chain(proposed_type).any(|name| listed(&self.document_types, &name))
+    }
+}
+
+/// Decides which documents go to the review queue during processing.
+pub struct ReviewQueue {
+    rules: ReviewRules,
+    names: ObjectNames,
+}
+
+impl ReviewQueue {
+    /// `None` if no document needs a review.
+    pub async fn load(client: &Client, base_url: &str, rules: &ReviewRules) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        if !rules.is_enabled() {
+            return Ok(None);
+        }
+        let names = ObjectNames::load(client, base_url).await?;
+        Ok(Some(ReviewQueue { rules: rules.clone(), names }))
+    }
+
+    pub fn requires_review(&self, update: &DocumentUpdate, document: &Document) -> bool {
+        self.rules.requires_review(update, document, &self.names)
+    }
+}
+
+pub fn list(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let state = open_state(config)?;
+    let proposals = state.pending_proposals()?;
+    if proposals.is_empty() {
+        println!("No pending proposals");
+    }
+    for proposal in proposals {
+        println!("{:>6}  document {:>6}  {}  \"{}\"", proposal.id, proposal.document_id, proposal.created_at, proposal.document_title);
+    }
+    Ok(())
+}
+
+/// Prints what the proposal would change on the document as it is now.
+pub async fn show(client: &Client, config: &Config, proposal_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+    let state = open_state(config)?;
+    let proposal = pending(&state, proposal_id)?;
+    let document = get_document(client, &config.base_url, proposal.document_id).await?;
+    let fields = query_custom_fields(client, &config.base_url).await?;
+    let names = ObjectNames::load(client, &config.base_url).await?;
+    println!("Proposal {} from {}", proposal.id, proposal.created_at);
+    warn_if_modified(&proposal, &document);
+    println!("{}", dry_run::report(&proposal.update, &document, &fields, &names));
+    Ok(())
+}
+
+/// Opens the proposed changes as JSON in `$VISUAL` or `$EDITOR`.
+pub fn edit(config: &Config, proposal_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+    let state = open_state(config)?;
+    let proposal = pending(&state, proposal_id)?;
+    let path = env::temp_dir().join(format!("doclytics-proposal-{}.json", proposal_id));
+    fs::write(&path, serde_json::to_string_pretty(&proposal.update)?)?;
+
+    let editor = env::var("VISUAL").or_else(|_| env::var("EDITOR")).unwrap_or_else(|_| "vi".to_string());
+    let mut command = editor.split_whitespace();
+    let program = command.next().ok_or_else(|| ResponseError::Other("EDITOR is empty".to_string()))?;
+    let status = std::process::Command::new(program).args(command).arg(&path).status()?;
+    let edited = fs::read_to_string(&path);
+    let _ = fs::remove_file(&path);
+    if !status.success() {
+        return Err(ResponseError::Other(format!("{} exited with {}, proposal not changed", editor, status)).into());
+    }
+
+    let update: DocumentUpdate = serde_json::from_str(&edited?).map_err(|e| ResponseError::Other(format!("invalid proposal, not changed: {}", e)))?;
+    if update.document_id != proposal.document_id {
+        return Err(ResponseError::Other("the document_id of a proposal cannot be changed".to_string()).into());
+    }
+    if update == proposal.update {
+        println!("Proposal {} not changed", proposal_id);
+        return Ok(());
+    }
+    state.update_proposal(proposal_id, &update)?;
+    println!("Updated proposal {}", proposal_id);
+    Ok(())
+}
+
+/// Applies the proposals with the same code path as an unreviewed update.
+pub async fn approve(client: &Client, config: &Config, proposal_ids: &[i64]) -> Result<(), Box<dyn std::error::Error>> {
+    decide(client, config, proposal_ids, ProposalStatus::Approved).await
+}
+
+/// Discards the proposals, the documents are only marked as processed.
+pub async fn reject(client: &Client, config: &Config, proposal_ids: &[i64]) -> Result<(), Box<dyn std::error::Error>> {
+    decide(client, config, proposal_ids, ProposalStatus::Rejected).await
+}
+
+async fn decide(client: &Client, config: &Config, proposal_ids: &[i64], decision: ProposalStatus) -> Result<(), Box<dyn std::error::Error>> {
+    let base_url = config.base_url.as_str();
+    let state = open_state(config)?;
+    let mut fields = query_custom_fields(client, base_url).await?;
+    let marker = Marker::bootstrap(client, base_url, &config.marker, &mut fields, false).await?;
+    let fields = Mutex::new(fields);
+    // Started with the first approved change, rejections only set the marker
+    let mut run_id = None;
+
+    let mut failed = 0;
+    for proposal_id in proposal_ids {
+        let result = async {
+            let proposal = pending(&state, *proposal_id)?;
+            let document = get_document(client, base_url, proposal.document_id).await?;
+            let (mut update, journal) = match decision {
+                ProposalStatus::Approved => {
+                    check_unmodified(&proposal, &document)?;
+                    let run_id = match run_id {
+                        Some(run_id) => run_id,
+                        None => {
+                            let started = state.start_run()?;
+                            slog_scope::info!("Started run {}", started);
+                            *run_id.insert(started)
+                        }
+                    };
+                    (proposal.update.clone(), Some(state.journal(run_id)))
+                }
+                _ => {
+                    warn_if_modified(&proposal, &document);
+                    (DocumentUpdate::new(proposal.document_id), None)
+                }
+            };
+            let payload = update.apply(client, base_url, &document, &fields, &marker, journal.as_ref()).await?;
+            state.decide_proposal(proposal.id, decision)?;
+            if decision == ProposalStatus::Approved {
+                record_approval(&state, proposal.document_id, Value::Object(payload))?;
+            }
+            Ok::<_, Box<dyn std::error::Error>>(proposal)
+        }
+        .await;
+        match result {
+            Ok(proposal) => slog_scope::info!("Proposal {} for document {} {}", proposal.id, proposal.document_id, decision.as_str()),
+            Err(e) => {
+                slog_scope::error!("Proposal {} not {}: {}", proposal_id, decision.as_str(), e);
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(ResponseError::Other(format!("{} of {} proposals failed", failed, proposal_ids.len())).into()),
+    }
+}
+
+/// Records the queued result as applied, so the document counts as up to
+/// date for `reprocess-stale`.
+fn record_approval(state: &StateStore, document_id: u32, applied_changes: Value) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(mut record) = state.last_result(document_id, Status::Queued)? {
+        record.status = Status::Updated;
+        record.applied_changes = Some(applied_changes);
+        record.finished_at = chrono::Utc::now().to_rfc3339();
+        state.record(&record)?;
+    }
+    Ok(())
+}
+
+fn pending(state: &StateStore, proposal_id: i64) -> Result<Proposal, Box<dyn std::error::Error>> {
+    match state.proposal(proposal_id)? {
+        Some(proposal) if proposal.status == ProposalStatus::Pending => Ok(proposal),
+        Some(proposal) => Err(ResponseError::Other(format!("proposal {} is {}", proposal_id, proposal.status.as_str())).into()),
+        None => Err(ResponseError::Other(format!("proposal {} does not exist", proposal_id)).into()),
+    }
+}
+
+/// The proposal holds the merged tags and values of the document at that
+/// time, applying it to a document changed since would undo those changes.
+fn check_unmodified(proposal: &Proposal, document: &Document) -> Result<(), Box<dyn std::error::Error>> {
+    if document.modified == proposal.document_modified {
+        return Ok(());
+    }
+    Err(ResponseError::Other(format!(
+        "document {} was modified since the changes were proposed, reject the proposal and run `doclytics process {}` to propose them again",
+        document.id, document.id
+    ))
+    .into())
+}
+
+fn warn_if_modified(proposal: &Proposal, document: &Document) {
+    if document.modified != proposal.document_modified {
+        println!("Warning: document {} was modified since the changes were proposed", document.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document_update::ObjectRef;
+    use crate::test_support::test_document;
+
+    #[test]
+    fn test_requires_review() {
+        let document = test_document(1);
+        let names = ObjectNames::default();
+        let rules = ReviewRules { all: false, tags: vec!["Tax".to_string()], document_types: vec!["Contract".to_string()] };
+
+        let mut update = DocumentUpdate::new(1);
+        update.tags = Some(vec![ObjectRef { id: Some(3), name: "Invoice".to_string() }]);
+        assert!(!rules.requires_review(&update, &document, &names));
+        update.tags = Some(vec![ObjectRef { id: None, name: "tax".to_string() }]);
+        assert!(rules.requires_review(&update, &document, &names));
+
+        let mut update = DocumentUpdate::new(1);
+        update.document_type = Some(ObjectRef { id: Some(2), name: "Contract".to_string() });
+        assert!(rules.requires_review(&update, &document, &names));
+        assert!(ReviewRules { all: true, ..Default::default() }.requires_review(&DocumentUpdate::new(1), &document, &names));
+
+        let mut proposal = Proposal {
+            id: 1,
+            document_id: 1,
+            document_title: "Scan".to_string(),
+            document_modified: "2024-03-12".to_string(),
+            update: DocumentUpdate::new(1),
+            status: ProposalStatus::Pending,
+            created_at: "2024-03-12".to_string(),
+        };
+        assert!(check_unmodified(&proposal, &document).is_ok());
+        proposal.document_modified = "2024-03-11".to_string();
+        assert!(check_unmodified(&proposal, &document).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use serde_json::{json, Map, Value};
+use crate::{Field, Mode};
+
+/// Builds the JSON schema for the custom field extraction answer.
+///
+/// Every known custom field (except the marker field) plus the document
+/// title becomes a nullable property. In `Mode::Create` the model may add
+/// keys for fields that do not exist in paperless yet.
+pub fn custom_fields_schema(fields: &[Field], mode: Mode, marker_field: Option<u32>) -> Value {
+    let mut properties = Map::new();
+    properties.insert("title".to_string(), json!({ "type": ["string", "null"] }));
+    for field in fields.iter().filter(|f| Some(f.id) != marker_field) {
+        properties.insert(field.name.clone(), field_schema(field));
+    }
+    let required: Vec<&String> = properties.keys().collect();
+
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": matches!(mode, Mode::Create),
+    })
+}
+
+/// Schema for the tag, document type and correspondent answers.
+pub fn string_array_schema() -> Value {
+    json!({
+        "type": "array",
+        "items": { "type": "string" },
+    })
+}
+
+fn field_schema(field: &Field) -> Value {
+    let mut schema = json!({ "type": [json_type(&field.data_type), "null"] });
+    let options = field.extra_data.as_ref().map(|extra| extra.select_options.as_slice()).unwrap_or_default();
+    if field.data_type == "select" && !options.is_empty() {
+        let mut labels: Vec<Value> = options.iter().map(|option| json!(option.label())).collect();
+        labels.push(Value::Null);
+        schema["enum"] = Value::Array(labels);
+    }
+    schema
+}
+
+fn json_type(data_type: &str) -> &'static str {
+    match data_type {
+        "boolean" => "boolean",
+        "integer" | "documentlink" => "integer",
+        "float" => "number",
+        _ => "string",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(id: u32, name: &str, data_type: &str) -> Field {
+        Field {
+            id,
+            name: name.to_string(),
+            data_type: data_type.to_string(),
+            extra_data: None,
+        }
+    }
+
+    #[test]
+    fn test_custom_fields_schema() {
+        let fields = vec![
+            field(1, "tagged", "boolean"),
+            field(2, "sender", "string"),
+            field(3, "amount", "float"),
+        ];
+        let schema = custom_fields_schema(&fields, Mode::NoCreate, Some(1));
+
+        assert_eq!(schema["properties"]["sender"]["type"], json!(["string", "null"]));
+        assert_eq!(schema["properties"]["amount"]["type"], json!(["number", "null"]));
+        assert!(schema["properties"].get("tagged").is_none());
+        assert_eq!(schema["required"], json!(["title", "sender", "amount"]));
+        assert_eq!(schema["additionalProperties"], json!(false));
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Set once SIGTERM or Ctrl-C is received. The document in flight is
+/// finished, no new document is started afterwards.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    notify: Arc<Notify>,
+}
+
+impl Shutdown {
+    /// Starts listening for SIGTERM and Ctrl-C.
+    pub fn listen() -> Self {
+        let shutdown = Shutdown::default();
+        let handle = shutdown.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            slog_scope::info!("Shutdown requested, finishing the current document");
+            handle.request();
+        });
+        shutdown
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once a shutdown is requested.
+    pub async fn wait(&self) {
+        let notified = self.notify.notified();
+        if !self.is_requested() {
+            notified.await;
+        }
+    }
+
+    /// Sleeps for `duration`, returns `false` if it was cut short by a
+    /// shutdown.
+    pub async fn sleep(&self, duration: Duration) -> bool {
+        let notified = self.notify.notified();
+        if self.is_requested() {
+            return false;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => !self.is_requested(),
+            _ = notified => false,
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Err(e) => {
+            slog_scope::warn!("Could not listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown_interrupts_sleep() {
+        let shutdown = Shutdown::default();
+        let handle = shutdown.clone();
+        tokio::spawn(async move { handle.request() });
+        assert!(!shutdown.sleep(Duration::from_secs(60)).await);
+        assert!(shutdown.is_requested());
+    }
+}
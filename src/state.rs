@@ -0,0 +1,606 @@
+use std::path::Path;
+use std::sync::Mutex;
+use chrono::{SecondsFormat, Utc};
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use crate::Document;
+use crate::document_update::DocumentUpdate;
+use crate::error::ResponseError;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS results (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    document_id INTEGER NOT NULL,
+    content_hash TEXT NOT NULL,
+    model TEXT NOT NULL,
+    prompt_version TEXT NOT NULL,
+    raw_response TEXT NOT NULL,
+    parsed_result TEXT NOT NULL,
+    applied_changes TEXT,
+    status TEXT NOT NULL,
+    error TEXT,
+    started_at TEXT NOT NULL,
+    finished_at TEXT NOT NULL,
+    repairs TEXT NOT NULL DEFAULT '{}'
+);
+CREATE INDEX IF NOT EXISTS results_document_id ON results (document_id);
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    started_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS journal (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    run_id INTEGER NOT NULL,
+    document_id INTEGER NOT NULL,
+    previous TEXT NOT NULL,
+    changes TEXT NOT NULL,
+    applied_at TEXT NOT NULL,
+    rolled_back_at TEXT
+);
+CREATE INDEX IF NOT EXISTS journal_document_id ON journal (document_id);
+CREATE TABLE IF NOT EXISTS rolled_back_documents (
+    document_id INTEGER PRIMARY KEY,
+    rolled_back_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS proposals (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    document_id INTEGER NOT NULL,
+    document_title TEXT NOT NULL,
+    document_modified TEXT NOT NULL,
+    proposed_update TEXT NOT NULL,
+    status TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    decided_at TEXT
+);
+CREATE INDEX IF NOT EXISTS proposals_document_id ON proposals (document_id);
+";
+
+const COLUMNS: &str = "document_id, content_hash, model, prompt_version, raw_response, parsed_result, applied_changes, status, error, started_at, finished_at, repairs";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Updated,
+    /// The LLM could not be asked or its answer not be parsed
+    AnalysisFailed,
+    UpdateFailed,
+    /// Waiting for approval in the review queue
+    Queued,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Updated => "updated",
+            Status::AnalysisFailed => "analysis_failed",
+            Status::UpdateFailed => "update_failed",
+            Status::Queued => "queued",
+        }
+    }
+
+    fn parse(value: &str) -> Status {
+        match value {
+            "updated" => Status::Updated,
+            "queued" => Status::Queued,
+            "analysis_failed" => Status::AnalysisFailed,
+            _ => Status::UpdateFailed,
+        }
+    }
+}
+
+/// One processing of a document: what the LLM was asked and answered and
+/// what was written to paperless.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub document_id: u32,
+    pub content_hash: String,
+    pub model: String,
+    pub prompt_version: String,
+    /// Raw LLM answer per prompt
+    pub raw_response: Value,
+    /// Parsed LLM answer per prompt
+    pub parsed_result: Value,
+    /// The payload sent to paperless, `None` if the update failed
+    pub applied_changes: Option<Value>,
+    pub status: Status,
+    pub error: Option<String>,
+    pub started_at: String,
+    pub finished_at: String,
+    /// Repairs applied to the answer of each prompt before it could be
+    /// parsed, only prompts whose answer needed any
+    pub repairs: Value,
+}
+
+impl Record {
+    fn from_row(row: &Row) -> rusqlite::Result<Record> {
+        let json = |index: usize| -> rusqlite::Result<Value> {
+            let text: String = row.get(index)?;
+            Ok(serde_json::from_str(&text).unwrap_or(Value::String(text)))
+        };
+        let applied_changes: Option<String> = row.get(6)?;
+        Ok(Record {
+            document_id: row.get(0)?,
+            content_hash: row.get(1)?,
+            model: row.get(2)?,
+            prompt_version: row.get(3)?,
+            raw_response: json(4)?,
+            parsed_result: json(5)?,
+            applied_changes: applied_changes.map(|text| serde_json::from_str(&text).unwrap_or(Value::String(text))),
+            status: Status::parse(&row.get::<_, String>(7)?),
+            error: row.get(8)?,
+            started_at: row.get(9)?,
+            finished_at: row.get(10)?,
+            repairs: json(11)?,
+        })
+    }
+}
+
+/// The values a document had before doclytics changed it.
+#[derive(Debug, Clone)]
+pub struct JournalEntry {
+    pub id: i64,
+    pub run_id: i64,
+    pub document_id: u32,
+    /// The previous values of the fields in `changes`
+    pub previous: Value,
+    /// The payload sent to paperless
+    pub changes: Value,
+    pub applied_at: String,
+    pub rolled_back_at: Option<String>,
+}
+
+impl JournalEntry {
+    fn from_row(row: &Row) -> rusqlite::Result<JournalEntry> {
+        let json = |index: usize| -> rusqlite::Result<Value> {
+            let text: String = row.get(index)?;
+            Ok(serde_json::from_str(&text).unwrap_or(Value::String(text)))
+        };
+        Ok(JournalEntry {
+            id: row.get(0)?,
+            run_id: row.get(1)?,
+            document_id: row.get(2)?,
+            previous: json(3)?,
+            changes: json(4)?,
+            applied_at: row.get(5)?,
+            rolled_back_at: row.get(6)?,
+        })
+    }
+}
+
+/// Selects journal entries, every condition that is set has to match.
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub run_id: Option<i64>,
+    pub document_ids: Vec<u32>,
+    /// Timestamps as written by `timestamp`
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProposalStatus {
+    Pending,
+    Approved,
+    Rejected,
+    /// Replaced by a newer proposal for the same document
+    Superseded,
+}
+
+impl ProposalStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ProposalStatus::Pending => "pending",
+            ProposalStatus::Approved => "approved",
+            ProposalStatus::Rejected => "rejected",
+            ProposalStatus::Superseded => "superseded",
+        }
+    }
+
+    fn parse(value: &str) -> ProposalStatus {
+        match value {
+            "pending" => ProposalStatus::Pending,
+            "approved" => ProposalStatus::Approved,
+            "rejected" => ProposalStatus::Rejected,
+            _ => ProposalStatus::Superseded,
+        }
+    }
+}
+
+/// Changes held back until someone approves them.
+#[derive(Debug, Clone)]
+pub struct Proposal {
+    pub id: i64,
+    pub document_id: u32,
+    pub document_title: String,
+    /// `modified` of the document when the changes were proposed
+    pub document_modified: String,
+    pub update: DocumentUpdate,
+    pub status: ProposalStatus,
+    pub created_at: String,
+}
+
+const PROPOSAL_COLUMNS: &str = "id, document_id, document_title, document_modified, proposed_update, status, created_at";
+
+impl Proposal {
+    fn from_row(row: &Row) -> rusqlite::Result<Proposal> {
+        let update: String = row.get(4)?;
+        Ok(Proposal {
+            id: row.get(0)?,
+            document_id: row.get(1)?,
+            document_title: row.get(2)?,
+            document_modified: row.get(3)?,
+            update: serde_json::from_str(&update)
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Text, Box::new(e)))?,
+            status: ProposalStatus::parse(&row.get::<_, String>(5)?),
+            created_at: row.get(6)?,
+        })
+    }
+}
+
+/// Saves the previous values of the documents changed in one run.
+pub struct Journal<'a> {
+    store: &'a StateStore,
+    pub run_id: i64,
+}
+
+impl Journal<'_> {
+    /// Returns the id of the entry, to discard it if the change fails.
+    pub fn record(&self, document_id: u32, previous: &Map<String, Value>, changes: &Map<String, Value>) -> Result<i64, Box<dyn std::error::Error>> {
+        let connection = self.store.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        connection.execute(
+            "INSERT INTO journal (run_id, document_id, previous, changes, applied_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![self.run_id, document_id, Value::Object(previous.clone()).to_string(), Value::Object(changes.clone()).to_string(), timestamp()],
+        )?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    pub fn discard(&self, entry_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        let connection = self.store.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        connection.execute("DELETE FROM journal WHERE id = ?1", params![entry_id])?;
+        Ok(())
+    }
+}
+
+/// SQLite database keeping every result, so runs can be audited and
+/// unchanged documents are not sent to the LLM again.
+pub struct StateStore {
+    connection: Mutex<Connection>,
+}
+
+impl StateStore {
+    pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        Self::init(Connection::open(path)?)
+    }
+
+    fn init(connection: Connection) -> Result<Self, Box<dyn std::error::Error>> {
+        connection.execute_batch(SCHEMA)?;
+        // Databases created before the repairs were recorded
+        let has_repairs: bool = connection.query_row("SELECT COUNT(*) > 0 FROM pragma_table_info('results') WHERE name = 'repairs'", [], |row| row.get(0))?;
+        if !has_repairs {
+            connection.execute("ALTER TABLE results ADD COLUMN repairs TEXT NOT NULL DEFAULT '{}'", [])?;
+        }
+        Ok(StateStore { connection: Mutex::new(connection) })
+    }
+
+    pub fn record(&self, record: &Record) -> Result<(), Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        connection.execute(
+            &format!("INSERT INTO results ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)", COLUMNS),
+            params![
+                record.document_id,
+                record.content_hash,
+                record.model,
+                record.prompt_version,
+                record.raw_response.to_string(),
+                record.parsed_result.to_string(),
+                record.applied_changes.as_ref().map(Value::to_string),
+                record.status.as_str(),
+                record.error,
+                record.started_at,
+                record.finished_at,
+                record.repairs.to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The most recent result of the document with `status`.
+    pub fn last_result(&self, document_id: u32, status: Status) -> Result<Option<Record>, Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        let record = connection
+            .query_row(
+                &format!("SELECT {} FROM results WHERE document_id = ?1 AND status = ?2 ORDER BY id DESC LIMIT 1", COLUMNS),
+                params![document_id, status.as_str()],
+                Record::from_row,
+            )
+            .optional()?;
+        Ok(record)
+    }
+
+    /// Every result of the document, oldest first.
+    pub fn history(&self, document_id: u32) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        let mut statement = connection.prepare(&format!("SELECT {} FROM results WHERE document_id = ?1 ORDER BY id", COLUMNS))?;
+        let records = statement.query_map(params![document_id], Record::from_row)?.collect::<rusqlite::Result<Vec<Record>>>()?;
+        Ok(records)
+    }
+
+    /// Starts a new run, changes applied in it are journaled under its id.
+    pub fn start_run(&self) -> Result<i64, Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        connection.execute("INSERT INTO runs (started_at) VALUES (?1)", params![timestamp()])?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    pub fn journal(&self, run_id: i64) -> Journal<'_> {
+        Journal { store: self, run_id }
+    }
+
+    /// The entries matching `selection` that were not rolled back yet,
+    /// newest first.
+    pub fn journal_entries(&self, selection: &Selection) -> Result<Vec<JournalEntry>, Box<dyn std::error::Error>> {
+        let mut conditions = vec!["rolled_back_at IS NULL".to_string()];
+        let mut values: Vec<rusqlite::types::Value> = Vec::new();
+        if let Some(run_id) = selection.run_id {
+            values.push(run_id.into());
+            conditions.push(format!("run_id = ?{}", values.len()));
+        }
+        if !selection.document_ids.is_empty() {
+            let placeholders: Vec<String> = selection
+                .document_ids
+                .iter()
+                .map(|id| {
+                    values.push(i64::from(*id).into());
+                    format!("?{}", values.len())
+                })
+                .collect();
+            conditions.push(format!("document_id IN ({})", placeholders.join(", ")));
+        }
+        if let Some(since) = &selection.since {
+            values.push(since.clone().into());
+            conditions.push(format!("applied_at >= ?{}", values.len()));
+        }
+        if let Some(until) = &selection.until {
+            values.push(until.clone().into());
+            conditions.push(format!("applied_at < ?{}", values.len()));
+        }
+        let query = format!(
+            "SELECT id, run_id, document_id, previous, changes, applied_at, rolled_back_at FROM journal WHERE {} ORDER BY id DESC",
+            conditions.join(" AND ")
+        );
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        let mut statement = connection.prepare(&query)?;
+        let entries = statement.query_map(params_from_iter(values), JournalEntry::from_row)?.collect::<rusqlite::Result<Vec<JournalEntry>>>()?;
+        Ok(entries)
+    }
+
+    /// Every journal entry of the document, oldest first.
+    pub fn document_journal(&self, document_id: u32) -> Result<Vec<JournalEntry>, Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        let mut statement = connection.prepare(
+            "SELECT id, run_id, document_id, previous, changes, applied_at, rolled_back_at FROM journal WHERE document_id = ?1 ORDER BY id",
+        )?;
+        let entries = statement.query_map(params![document_id], JournalEntry::from_row)?.collect::<rusqlite::Result<Vec<JournalEntry>>>()?;
+        Ok(entries)
+    }
+
+    /// Queues the changes for review, replacing a pending proposal for the
+    /// same document. Returns the id of the proposal.
+    pub fn add_proposal(&self, document: &Document, update: &DocumentUpdate) -> Result<i64, Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        connection.execute(
+            "UPDATE proposals SET status = ?1, decided_at = ?2 WHERE document_id = ?3 AND status = ?4",
+            params![ProposalStatus::Superseded.as_str(), timestamp(), document.id, ProposalStatus::Pending.as_str()],
+        )?;
+        connection.execute(
+            "INSERT INTO proposals (document_id, document_title, document_modified, proposed_update, status, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![document.id, document.title, document.modified, serde_json::to_string(update)?, ProposalStatus::Pending.as_str(), timestamp()],
+        )?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    pub fn proposal(&self, proposal_id: i64) -> Result<Option<Proposal>, Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        let proposal = connection
+            .query_row(&format!("SELECT {} FROM proposals WHERE id = ?1", PROPOSAL_COLUMNS), params![proposal_id], Proposal::from_row)
+            .optional()?;
+        Ok(proposal)
+    }
+
+    /// The proposals waiting for a decision, oldest first.
+    pub fn pending_proposals(&self) -> Result<Vec<Proposal>, Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        let mut statement = connection.prepare(&format!("SELECT {} FROM proposals WHERE status = ?1 ORDER BY id", PROPOSAL_COLUMNS))?;
+        let proposals = statement
+            .query_map(params![ProposalStatus::Pending.as_str()], Proposal::from_row)?
+            .collect::<rusqlite::Result<Vec<Proposal>>>()?;
+        Ok(proposals)
+    }
+
+    pub fn has_pending_proposal(&self, document_id: u32) -> Result<bool, Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        let count: i64 = connection.query_row(
+            "SELECT COUNT(*) FROM proposals WHERE document_id = ?1 AND status = ?2",
+            params![document_id, ProposalStatus::Pending.as_str()],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    /// Replaces the proposed changes, e.g. after they were edited.
+    pub fn update_proposal(&self, proposal_id: i64, update: &DocumentUpdate) -> Result<(), Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        connection.execute("UPDATE proposals SET proposed_update = ?1 WHERE id = ?2", params![serde_json::to_string(update)?, proposal_id])?;
+        Ok(())
+    }
+
+    pub fn decide_proposal(&self, proposal_id: i64, status: ProposalStatus) -> Result<(), Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        connection.execute("UPDATE proposals SET status = ?1, decided_at = ?2 WHERE id = ?3", params![status.as_str(), timestamp(), proposal_id])?;
+        Ok(())
+    }
+
+    /// Whether the latest change of the document was rolled back.
+    pub fn is_rolled_back(&self, document_id: u32) -> Result<bool, Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        let rolled_back = connection
+            .query_row(
+                "SELECT rolled_back_at IS NOT NULL FROM journal WHERE document_id = ?1 ORDER BY id DESC LIMIT 1",
+                params![document_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(rolled_back.unwrap_or(false))
+    }
+
+    /// Marks the entry as rolled back. Its document is not processed again
+    /// until `clear_rollback` is called for it.
+    pub fn mark_rolled_back(&self, entry_id: i64) -> Result<(), Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        let now = timestamp();
+        connection.execute("UPDATE journal SET rolled_back_at = ?1 WHERE id = ?2", params![now, entry_id])?;
+        connection.execute(
+            "INSERT OR REPLACE INTO rolled_back_documents (document_id, rolled_back_at) SELECT document_id, ?1 FROM journal WHERE id = ?2",
+            params![now, entry_id],
+        )?;
+        Ok(())
+    }
+
+    /// Whether the document was rolled back and has not been reset since.
+    pub fn awaits_reset(&self, document_id: u32) -> Result<bool, Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        let count: i64 = connection.query_row("SELECT COUNT(*) FROM rolled_back_documents WHERE document_id = ?1", params![document_id], |row| row.get(0))?;
+        Ok(count > 0)
+    }
+
+    /// Allows a rolled back document to be processed again, returns whether
+    /// it was rolled back.
+    pub fn clear_rollback(&self, document_id: u32) -> Result<bool, Box<dyn std::error::Error>> {
+        let connection = self.connection.lock().map_err(|e| ResponseError::Other(e.to_string()))?;
+        Ok(connection.execute("DELETE FROM rolled_back_documents WHERE document_id = ?1", params![document_id])? > 0)
+    }
+}
+
+/// The current time in the format used by the journal, which sorts
+/// chronologically as text.
+pub fn timestamp() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Hex encoded SHA-256 of `text`.
+pub fn hash(text: &str) -> String {
+    Sha256::digest(text.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::test_support::test_document;
+
+    fn record(document_id: u32, status: Status) -> Record {
+        Record {
+            document_id,
+            content_hash: hash("Invoice"),
+            model: "llama3".to_string(),
+            prompt_version: hash("prompt"),
+            raw_response: json!({ "custom_fields": "{\"sender\": \"ACME\"}" }),
+            parsed_result: json!({ "custom_fields": { "sender": "ACME" } }),
+            applied_changes: (status == Status::Updated).then(|| json!({ "title": "Invoice" })),
+            status,
+            error: None,
+            started_at: "2024-05-01T10:00:00+00:00".to_string(),
+            finished_at: "2024-05-01T10:00:05+00:00".to_string(),
+            repairs: json!({ "custom_fields": ["trailing commas"] }),
+        }
+    }
+
+    #[test]
+    fn test_record_and_query() {
+        let store = StateStore::init(Connection::open_in_memory().unwrap()).unwrap();
+        store.record(&record(1, Status::Updated)).unwrap();
+        store.record(&record(1, Status::UpdateFailed)).unwrap();
+        store.record(&record(2, Status::UpdateFailed)).unwrap();
+        store.record(&record(1, Status::AnalysisFailed)).unwrap();
+
+        let last = store.last_result(1, Status::Updated).unwrap().unwrap();
+        assert_eq!(last.parsed_result, json!({ "custom_fields": { "sender": "ACME" } }));
+        assert_eq!(last.applied_changes, Some(json!({ "title": "Invoice" })));
+        assert_eq!(last.repairs, json!({ "custom_fields": ["trailing commas"] }));
+        assert!(store.last_result(2, Status::Updated).unwrap().is_none());
+        assert_eq!(store.history(1).unwrap().iter().map(|r| r.status).collect::<Vec<_>>(), vec![Status::Updated, Status::UpdateFailed, Status::AnalysisFailed]);
+    }
+
+    #[test]
+    fn test_add_repairs_column() {
+        let schema = SCHEMA.replace(",\n    repairs TEXT NOT NULL DEFAULT '{}'", "");
+        assert_ne!(schema, SCHEMA);
+        let connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(&schema).unwrap();
+        connection
+            .execute(
+                "INSERT INTO results (document_id, content_hash, model, prompt_version, raw_response, parsed_result, status, started_at, finished_at) VALUES (1, 'h', 'm', 'p', '{}', '{}', 'updated', 't', 't')",
+                [],
+            )
+            .unwrap();
+        let store = StateStore::init(connection).unwrap();
+        assert_eq!(store.last_result(1, Status::Updated).unwrap().unwrap().repairs, json!({}));
+    }
+
+    #[test]
+    fn test_proposals() {
+        let store = StateStore::init(Connection::open_in_memory().unwrap()).unwrap();
+        let document = Document { title: "Contract".to_string(), ..test_document(4) };
+        let mut update = DocumentUpdate::new(4);
+        update.title = Some("Rental contract".to_string());
+        let first = store.add_proposal(&document, &update).unwrap();
+        let second = store.add_proposal(&document, &update).unwrap();
+        assert_eq!(store.proposal(first).unwrap().unwrap().status, ProposalStatus::Superseded);
+        assert_eq!(store.pending_proposals().unwrap().iter().map(|p| p.id).collect::<Vec<_>>(), vec![second]);
+        assert!(store.has_pending_proposal(4).unwrap());
+
+        update.title = Some("Lease".to_string());
+        store.update_proposal(second, &update).unwrap();
+        store.decide_proposal(second, ProposalStatus::Approved).unwrap();
+        let proposal = store.proposal(second).unwrap().unwrap();
+        assert_eq!(proposal.update.title.as_deref(), Some("Lease"));
+        assert_eq!(proposal.status, ProposalStatus::Approved);
+        assert!(!store.has_pending_proposal(4).unwrap());
+    }
+
+    #[test]
+    fn test_journal_selection() {
+        let store = StateStore::init(Connection::open_in_memory().unwrap()).unwrap();
+        let previous = json!({ "title": "Scan" });
+        let changes = json!({ "title": "Invoice" });
+        let (previous, changes) = (previous.as_object().unwrap(), changes.as_object().unwrap());
+        let first = store.journal(store.start_run().unwrap());
+        first.record(1, previous, changes).unwrap();
+        let discarded = first.record(2, previous, changes).unwrap();
+        first.discard(discarded).unwrap();
+        let second = store.journal(store.start_run().unwrap());
+        let entry = second.record(1, previous, changes).unwrap();
+        second.record(3, previous, changes).unwrap();
+
+        let ids = |selection: Selection| store.journal_entries(&selection).unwrap().iter().map(|e| e.document_id).collect::<Vec<_>>();
+        assert_eq!(ids(Selection { run_id: Some(second.run_id), ..Default::default() }), vec![3, 1]);
+        assert_eq!(ids(Selection { document_ids: vec![1, 2], ..Default::default() }), vec![1, 1]);
+        assert_eq!(ids(Selection { until: Some("2000-01-01T00:00:00Z".to_string()), ..Default::default() }), Vec::<u32>::new());
+
+        assert!(!store.is_rolled_back(1).unwrap());
+        store.mark_rolled_back(entry).unwrap();
+        assert_eq!(ids(Selection { document_ids: vec![1], ..Default::default() }), vec![1]);
+        assert!(store.is_rolled_back(1).unwrap());
+        assert!(store.document_journal(1).unwrap()[1].rolled_back_at.is_some());
+
+        assert!(store.awaits_reset(1).unwrap());
+        assert!(!store.awaits_reset(3).unwrap());
+        assert!(store.clear_rollback(1).unwrap());
+        assert!(!store.clear_rollback(1).unwrap());
+        assert!(!store.awaits_reset(1).unwrap());
+        assert!(store.is_rolled_back(1).unwrap());
+    }
+}
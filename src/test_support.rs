@@ -0,0 +1,25 @@
+use crate::Document;
+
+/// A document without tags, type or custom fields.
+pub fn test_document(id: u32) -> Document {
+    Document {
+        id,
+        correspondent: None,
+        document_type: None,
+        storage_path: None,
+        title: "Scan".to_string(),
+        content: String::new(),
+        created: "2024-03-12".to_string(),
+        created_date: None,
+        modified: "2024-03-12".to_string(),
+        added: "2024-03-12".to_string(),
+        archive_serial_number: None,
+        original_file_name: None,
+        archived_file_name: None,
+        owner: None,
+        notes: Vec::new(),
+        tags: Vec::new(),
+        user_can_change: true,
+        custom_fields: Vec::new(),
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::routing::post;
+use axum::Router;
+use reqwest::Client;
+use serde_json::Value;
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use crate::{process_document, Outcome, ProcessingContext, Reprocess};
+use crate::config::Config;
+use crate::error::ResponseError;
+use crate::llm_api::LlmBackend;
+use crate::paperless::{get_document, query_custom_fields};
+use crate::shutdown::Shutdown;
+
+/// Header carrying the shared secret, `Authorization: Bearer <secret>` is
+/// accepted as well.
+const SECRET_HEADER: &str = "x-doclytics-secret";
+
+/// Documents waiting to be processed. A document notified again within the
+/// dedup window is not queued a second time.
+struct Queue {
+    sender: mpsc::UnboundedSender<u32>,
+    seen: Mutex<HashMap<u32, Instant>>,
+    dedup_window: Duration,
+}
+
+impl Queue {
+    /// Returns `false` if the document was already queued recently.
+    fn push(&self, document_id: u32) -> bool {
+        let Ok(mut seen) = self.seen.lock() else {
+            return false;
+        };
+        let now = Instant::now();
+        seen.retain(|_, queued| now.duration_since(*queued) < self.dedup_window);
+        if seen.contains_key(&document_id) {
+            return false;
+        }
+        seen.insert(document_id, now);
+        self.sender.send(document_id).is_ok()
+    }
+
+    /// Allows a failed document to be queued again right away.
+    fn forget(&self, document_id: u32) {
+        if let Ok(mut seen) = self.seen.lock() {
+            seen.remove(&document_id);
+        }
+    }
+
+    /// Logs the result of a queued document and forgets it unless it was
+    /// processed, so the retries of Paperless are not dropped.
+    fn finish(&self, document_id: u32, result: Result<Outcome, Box<dyn std::error::Error>>) {
+        let error = match result {
+            Ok(Outcome::Skipped | Outcome::Updated | Outcome::Reported | Outcome::Queued) => return,
+            Ok(Outcome::AnalysisFailed(e) | Outcome::UpdateFailed(e)) | Err(e) => e,
+        };
+        slog_scope::error!("Error processing document {} from webhook: {}", document_id, error);
+        self.forget(document_id);
+    }
+}
+
+struct WebhookState {
+    secret: String,
+    queue: Queue,
+}
+
+/// Accepts document-added notifications on `POST /webhook` and processes the
+/// documents one after another until a shutdown is requested.
+pub async fn run(client: &Client, llm: &dyn LlmBackend, config: &Config, shutdown: Shutdown) -> Result<(), Box<dyn std::error::Error>> {
+    let secret = config
+        .webhook_secret
+        .clone()
+        .ok_or_else(|| ResponseError::Other("webhook.secret is required, set WEBHOOK_SECRET".to_string()))?;
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let state = Arc::new(WebhookState {
+        secret,
+        queue: Queue { sender, seen: Mutex::new(HashMap::new()), dedup_window: config.webhook_dedup_window },
+    });
+    let app = Router::new().route("/webhook", post(notify)).with_state(state.clone());
+
+    let listener = tokio::net::TcpListener::bind(config.webhook_listen).await?;
+    slog_scope::info!("Listening for webhooks on {}", config.webhook_listen);
+    let server_shutdown = shutdown.clone();
+    let server = tokio::spawn(async move {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move { server_shutdown.wait().await })
+            .await
+    });
+
+    loop {
+        let document_id = tokio::select! {
+            document_id = receiver.recv() => match document_id {
+                Some(document_id) => document_id,
+                None => break,
+            },
+            _ = shutdown.wait() => break,
+        };
+        let result = process(client, llm, config, &shutdown, document_id).await;
+        state.queue.finish(document_id, result);
+    }
+    server.await??;
+    slog_scope::info!("Webhook server stopped");
+    Ok(())
+}
+
+async fn process(client: &Client, llm: &dyn LlmBackend, config: &Config, shutdown: &Shutdown, document_id: u32) -> Result<Outcome, Box<dyn std::error::Error>> {
+    let mut fields = query_custom_fields(client, &config.base_url).await?;
+    let context = ProcessingContext::new(client, llm, config, &mut fields, config.dry_run, Reprocess::Never, shutdown.clone()).await?;
+    let document = get_document(client, &config.base_url, document_id).await?;
+    Ok(process_document(&context, &AsyncMutex::new(fields), &document).await)
+}
+
+async fn notify(State(state): State<Arc<WebhookState>>, headers: HeaderMap, body: Bytes) -> (StatusCode, String) {
+    if !is_authorized(&headers, &state.secret) {
+        slog_scope::warn!("Rejected webhook with missing or wrong secret");
+        return (StatusCode::UNAUTHORIZED, "invalid secret".to_string());
+    }
+    let Some(document_id) = parse_document_id(&body) else {
+        return (StatusCode::BAD_REQUEST, "no document id found, send {\"document_id\": <id>} or {\"url\": <document url>}".to_string());
+    };
+    if state.queue.push(document_id) {
+        slog_scope::info!("Queued document {} from webhook", document_id);
+        (StatusCode::ACCEPTED, format!("queued document {}", document_id))
+    } else {
+        slog_scope::debug!("Document {} is already queued", document_id);
+        (StatusCode::OK, format!("document {} is already queued", document_id))
+    }
+}
+
+fn is_authorized(headers: &HeaderMap, secret: &str) -> bool {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let provided = header(SECRET_HEADER).or_else(|| header("authorization").and_then(|value| value.strip_prefix("Bearer ")));
+    provided.is_some_and(|provided| constant_time_eq(provided.as_bytes(), secret.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Reads the document id from a notification. Accepts a JSON object with
+/// `document_id`, `id`, `url` or `doc_url` (as sent by Paperless workflows),
+/// a bare id or a document URL.
+fn parse_document_id(body: &[u8]) -> Option<u32> {
+    let text = std::str::from_utf8(body).ok()?.trim();
+    match serde_json::from_str::<Value>(text) {
+        Ok(Value::Object(object)) => {
+            let id = ["document_id", "id"].iter().filter_map(|key| object.get(*key)).find_map(|value| match value {
+                Value::Number(number) => number.as_u64().and_then(|id| u32::try_from(id).ok()),
+                Value::String(id) => id.trim().parse().ok(),
+                _ => None,
+            });
+            id.or_else(|| {
+                ["url", "doc_url", "document_url"]
+                    .iter()
+                    .filter_map(|key| object.get(*key).and_then(Value::as_str))
+                    .find_map(id_from_url)
+            })
+        }
+        Ok(Value::Number(number)) => number.as_u64().and_then(|id| u32::try_from(id).ok()),
+        Ok(Value::String(url)) => id_from_url(&url),
+        _ => id_from_url(text),
+    }
+}
+
+/// The last numeric path segment, e.g. 12 for
+/// `https://paperless.example.com/documents/12/details`.
+fn id_from_url(url: &str) -> Option<u32> {
+    url.split(['/', '?', '#']).rev().find_map(|segment| segment.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_document_id() {
+        assert_eq!(parse_document_id(br#"{"document_id": 12}"#), Some(12));
+        assert_eq!(parse_document_id(br#"{"id": "13"}"#), Some(13));
+        assert_eq!(parse_document_id(br#"{"doc_url": "http://paperless:8000/documents/14/details"}"#), Some(14));
+        assert_eq!(parse_document_id(b"https://paperless.example.com/api/documents/15/"), Some(15));
+        assert_eq!(parse_document_id(b"16"), Some(16));
+        assert_eq!(parse_document_id(br#"{"title": "Invoice"}"#), None);
+    }
+
+    #[test]
+    fn test_secret() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_authorized(&headers, "secret"));
+        headers.insert("authorization", "Bearer secret".parse().unwrap());
+        assert!(is_authorized(&headers, "secret"));
+        headers.insert(SECRET_HEADER, "wrong".parse().unwrap());
+        assert!(!is_authorized(&headers, "secret"));
+    }
+
+    #[test]
+    fn test_queue_deduplicates() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let queue = Queue { sender, seen: Mutex::new(HashMap::new()), dedup_window: Duration::from_secs(60) };
+        assert!(queue.push(1));
+        assert!(!queue.push(1));
+        assert!(queue.push(2));
+        assert_eq!(receiver.try_recv().ok(), Some(1));
+        assert_eq!(receiver.try_recv().ok(), Some(2));
+        assert!(receiver.try_recv().is_err());
+        queue.forget(1);
+        assert!(queue.push(1));
+    }
+
+    #[test]
+    fn test_failed_document_is_accepted_again() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let queue = Queue { sender, seen: Mutex::new(HashMap::new()), dedup_window: Duration::from_secs(60) };
+        assert!(queue.push(1));
+        queue.finish(1, Ok(Outcome::AnalysisFailed(Box::new(ResponseError::Other("timeout".to_string())))));
+        assert!(queue.push(1));
+        queue.finish(1, Err(Box::new(ResponseError::Other("paperless unreachable".to_string()))));
+        assert!(queue.push(1));
+        queue.finish(1, Ok(Outcome::Updated));
+        assert!(!queue.push(1));
+        assert_eq!(std::iter::from_fn(|| receiver.try_recv().ok()).count(), 3);
+    }
+}